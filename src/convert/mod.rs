@@ -0,0 +1,288 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use crate::error::PpmError;
+use crate::header::{read_header_tokens, write_header, Header, Tokenizer, ASCII_LINE_LENGTH};
+use crate::image::{Format, Image, Kind};
+use crate::p3::format_row;
+use crate::p6::{bytes_per_sample, decode_samples, fill_buffer};
+
+/*
+    Lossless conversions between the plain (ASCII) and raw (binary) encodings of netpbm images: P1 <-> P4, P2 <-> P5 and P3 <-> P6.
+    Samples are copied as they are, 8 or 16 bits ones alike, so converting an image back gives the original pixels.
+*/
+
+/*
+    convert(image, format)
+
+    Copy an in-memory image, which will be saved with the given encoding.
+    Its kind, dimensions, max value, comments and pixels are kept.
+*/
+pub fn convert(image: &Image, format: Format) -> Image {
+    Image {
+        pixels: image.pixels.clone(),
+        width: image.width,
+        height: image.height,
+        max_value: image.max_value,
+        format,
+        kind: image.kind,
+        comments: image.comments.clone(),
+    }
+}
+
+/*
+    convert_file(input, output, format)
+
+    Same as convert_stream(), from a file to another one.
+*/
+pub fn convert_file(input: &Path, output: &Path, format: Format) -> Result<(), PpmError> {
+    let mut writer = BufWriter::new(File::create(output)?);
+
+    convert_stream(BufReader::new(File::open(input)?), &mut writer, format)?;
+
+    writer.flush()?;
+    Ok(())
+}
+
+/*
+    convert_stream(reader, writer, format)
+
+    Transcode a netpbm image from the reader to the writer one row at a time, so images of any size can be converted.
+    Only the encoding changes, converting to the encoding the image already has rewrites it with our own layout.
+    The writer is not flushed.
+
+    Will return Result with Err if the image is invalid, ASCII errors come with their line and column.
+*/
+pub fn convert_stream<R: BufRead, W: Write>(mut reader: R, writer: &mut W, format: Format) -> Result<(), PpmError> {
+
+    let mut tokens = Tokenizer::new(&mut reader);
+    let header = read_header_tokens(&mut tokens)?;
+
+    write_header(writer, header.kind, format, (header.width, header.height), header.max_value, &header.comments)?;
+
+    let row_samples = header.width * channels(header.kind);
+    let mut row = vec![0; row_samples];
+    let mut bytes = vec![];
+
+    for y in 0..header.height {
+        read_row(&mut tokens, &header, y, &mut row, &mut bytes)?;
+        write_row(writer, &header, format, &row, &mut bytes)?;
+    }
+
+    Ok(())
+}
+
+// How many samples make a pixel.
+fn channels(kind: Kind) -> usize {
+    match kind {
+        Kind::Pixmap => 3,
+        _ => 1,
+    }
+}
+
+// Read the samples of row y, bitmaps samples are their bits (1 is black).
+fn read_row<R: BufRead>(tokens: &mut Tokenizer<R>, header: &Header, y: usize, row: &mut [u16], bytes: &mut Vec<u8>) -> Result<(), PpmError> {
+
+    let expected = header.width * header.height * channels(header.kind);
+    let row_start = y * row.len();
+    let truncated = |got: usize| PpmError::TruncatedPixelData { expected, got: row_start + got };
+
+    match (header.kind, header.format) {
+        (Kind::Bitmap, Format::Ascii) => {
+            for (index, sample) in row.iter_mut().enumerate() {
+                *sample = match tokens.next_char()? {
+                    Some((b'0', _, _)) => 0,
+                    Some((b'1', _, _)) => 1,
+                    Some((_, line, column)) => return Err(PpmError::InvalidSample { line, column }),
+                    None => return Err(truncated(index)),
+                };
+            }
+        }
+
+        // Packed bits, 8 pixels per byte with the most significant bit first, padded to a whole byte.
+        (Kind::Bitmap, Format::Binary) => {
+            bytes.resize(header.width.div_ceil(8), 0);
+
+            let filled = fill_buffer(tokens, bytes)?;
+            if filled < bytes.len() {
+                return Err(truncated((filled * 8).min(row.len())));
+            }
+
+            for (x, sample) in row.iter_mut().enumerate() {
+                *sample = ((bytes[x / 8] >> (7 - (x % 8))) & 1) as u16;
+            }
+        }
+
+        (_, Format::Ascii) => {
+            for (index, sample) in row.iter_mut().enumerate() {
+                let token = match tokens.next_token()? {
+                    Some(token) => token,
+                    None => return Err(truncated(index)),
+                };
+
+                *sample = match token.number() {
+                    Some(value) if value <= header.max_value => value as u16,
+                    _ => return Err(PpmError::InvalidSample { line: token.line, column: token.column }),
+                };
+            }
+        }
+
+        // Samples above the max value are refused like ASCII ones, so that converted images can be read back.
+        (_, Format::Binary) => {
+            let sample_bytes = bytes_per_sample(header.max_value);
+            bytes.resize(row.len() * sample_bytes, 0);
+
+            let start = tokens.position();
+            let filled = fill_buffer(tokens, bytes)?;
+            if filled < bytes.len() {
+                return Err(truncated(filled / sample_bytes));
+            }
+
+            decode_samples(bytes, header.max_value, || Ok(start), row)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Write the samples of a row with the given encoding, just like our image writers do.
+fn write_row<W: Write>(writer: &mut W, header: &Header, format: Format, row: &[u16], bytes: &mut Vec<u8>) -> Result<(), PpmError> {
+
+    bytes.clear();
+
+    match (header.kind, format) {
+        (Kind::Bitmap, Format::Ascii) => {
+            for line in row.chunks(ASCII_LINE_LENGTH) {
+                bytes.extend(line.iter().map(|bit| b'0' + *bit as u8));
+                bytes.push(b'\n');
+            }
+        }
+        (Kind::Bitmap, Format::Binary) => {
+            bytes.resize(header.width.div_ceil(8), 0);
+            for (x, bit) in row.iter().enumerate() {
+                bytes[x / 8] |= (*bit as u8) << (7 - (x % 8));
+            }
+        }
+        (_, Format::Ascii) => format_row(bytes, row.iter().copied()),
+        (_, Format::Binary) => match bytes_per_sample(header.max_value) {
+            1 => bytes.extend(row.iter().map(|sample| *sample as u8)),
+            _ => bytes.extend(row.iter().flat_map(|sample| sample.to_be_bytes())),
+        },
+    }
+
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+// Module for testing
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::image::decode;
+    use std::io::Cursor;
+
+    fn fixture(path: &str) -> Vec<u8> {
+        std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("src").join(path)).unwrap()
+    }
+
+    fn convert_bytes(input: &[u8], format: Format) -> Vec<u8> {
+        let mut output = vec![];
+        convert_stream(input, &mut output, format).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_round_trips() {
+        let fixtures = [
+            ("p3/test/test.ppm", Format::Ascii),
+            ("p3/test/deep.ppm", Format::Ascii),
+            ("p6/test/alaska.ppm", Format::Binary),
+            ("p6/test/deep.ppm", Format::Binary),
+            ("p6/test/legacy.ppm", Format::Binary),
+            ("pgm/test/greymap.pgm", Format::Ascii),
+            ("pgm/test/greymap.binary.pgm", Format::Binary),
+            ("pbm/test/bitmap.pbm", Format::Ascii),
+            ("pbm/test/bitmap.binary.pbm", Format::Binary),
+        ];
+
+        for (path, format) in fixtures {
+            let input = fixture(path);
+            let original = decode(Cursor::new(&input)).unwrap();
+
+            let other = match format {
+                Format::Ascii => Format::Binary,
+                Format::Binary => Format::Ascii,
+            };
+
+            let converted = convert_bytes(&input, other);
+            let back = convert_bytes(&converted, format);
+
+            for (bytes, expected_format) in [(&converted, other), (&back, format)] {
+                let image = decode(Cursor::new(bytes)).unwrap();
+
+                assert_eq!(expected_format, image.format(), "{}", path);
+                assert_eq!((original.kind(), original.max_value()), (image.kind(), image.max_value()), "{}", path);
+                assert_eq!((original.width(), original.height()), (image.width(), image.height()), "{}", path);
+                assert_eq!(original.pixels(), image.pixels(), "{}", path);
+            }
+
+            // Once in our own layout, converting back and forth gives the very same bytes.
+            assert_eq!(converted, convert_bytes(&back, other), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_matches_image_writers() {
+        for path in ["p6/test/alaska.ppm", "p3/test/deep.ppm", "pbm/test/bitmap.pbm"] {
+            let image = decode(Cursor::new(fixture(path))).unwrap();
+
+            for format in [Format::Ascii, Format::Binary] {
+                let converted = convert(&image, format);
+                assert_eq!(format, converted.format());
+                assert_eq!(image.pixels(), converted.pixels());
+
+                // pgm writes ASCII samples one per line, so greymaps are left out.
+                let mut expected = vec![];
+                converted.write_to(&mut expected, format).unwrap();
+                assert_eq!(expected, convert_bytes(&fixture(path), format), "{}", path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        match convert_stream("P3 2 1 255\n1 2 3\n4 5".as_bytes(), &mut vec![], Format::Binary) {
+            Err(PpmError::TruncatedPixelData { expected, got }) => assert_eq!((6, 5), (expected, got)),
+            _ => panic!("expected a truncated pixel data error"),
+        }
+
+        match convert_stream("P3 1 1 15\n1 16 3\n".as_bytes(), &mut vec![], Format::Binary) {
+            Err(PpmError::InvalidSample { line, column }) => assert_eq!((2, 3), (line, column)),
+            _ => panic!("expected an invalid sample error"),
+        }
+
+        // Binary samples above the max value can't be written as ASCII ones, they are refused with their position.
+        match convert_stream(&b"P6 1 1 15\n\xff\0\0"[..], &mut vec![], Format::Ascii) {
+            Err(PpmError::InvalidSample { line, column }) => assert_eq!((2, 1), (line, column)),
+            _ => panic!("expected an invalid sample error"),
+        }
+
+        match convert_stream(&b"P5 3 1 1000\n\x00\x01\x03\xe8\x03\xe9"[..], &mut vec![], Format::Ascii) {
+            Err(PpmError::InvalidSample { line, column }) => assert_eq!((2, 5), (line, column)),
+            _ => panic!("expected an invalid sample error"),
+        }
+
+        match crate::image::decode(&b"P6 1 1 15\n\x0f\x10\0"[..]) {
+            Err(PpmError::InvalidSample { line, column }) => assert_eq!((2, 2), (line, column)),
+            _ => panic!("expected an invalid sample error"),
+        }
+
+        match convert_stream(&b"P6 2 1 255\n\x01\x02\x03\x04"[..], &mut vec![], Format::Ascii) {
+            Err(PpmError::TruncatedPixelData { expected, got }) => assert_eq!((6, 4), (expected, got)),
+            _ => panic!("expected a truncated pixel data error"),
+        }
+    }
+}
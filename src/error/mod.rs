@@ -0,0 +1,75 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+/*
+    Our crate-wide error type.
+    Every public function returns it instead of panicking, so callers can tell a bad file from a failing disk.
+*/
+#[derive(Debug)]
+pub enum PpmError {
+    // Reading or writing failed at the OS level.
+    Io(io::Error),
+    // The file does not start with a magic number we know how to handle.
+    BadMagic(String),
+    // Width or height header is not a valid number.
+    BadDimension(String),
+    // Width or height is above header::MAX_DIMENSION, there are more than header::MAX_PIXELS pixels, or the pixels section wouldn't fit in memory addresses.
+    ImageTooLarge { width: usize, height: usize },
+    // The max value header is a number we can't store samples for.
+    UnsupportedMaxval(usize),
+    // Any other malformed header (unterminated comment, garbage value...).
+    BadHeader(String),
+    // The pixels section ended before width * height pixels were read, counted in samples.
+    TruncatedPixelData { expected: usize, got: usize },
+    // An ASCII sample couldn't be parsed, line and column are 1-based.
+    InvalidSample { line: usize, column: usize },
+    // A convolution kernel that can't be applied (even size, missing weights...).
+    InvalidKernel(String),
+    // A region which doesn't fit inside the image.
+    RegionOutOfBounds { x: usize, y: usize, width: usize, height: usize },
+    // The process was stopped through its cancellation token.
+    Cancelled,
+    // A pixels operation (a PixelOp, a NeighborhoodOp...) panicked while transforming a chunk.
+    ProcessingFailed,
+}
+
+impl fmt::Display for PpmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PpmError::Io(e) => write!(f, "I/O error ({}).", e),
+            PpmError::BadMagic(magic_number) => write!(f, "Unknown magic number ({}).", magic_number),
+            PpmError::BadDimension(value) => write!(f, "Could not parse dimension header into a number ({}).", value),
+            PpmError::ImageTooLarge { width, height } => write!(f, "Image of {}x{} pixels is too large.", width, height),
+            PpmError::UnsupportedMaxval(value) => write!(f, "Unsupported max value ({}).", value),
+            PpmError::BadHeader(msg) => write!(f, "Malformed header ({}).", msg),
+            PpmError::TruncatedPixelData { expected, got } => {
+                write!(f, "Pixel data is truncated (expected {} samples, got {}).", expected, got)
+            }
+            PpmError::InvalidSample { line, column } => {
+                write!(f, "Invalid sample at line {}, column {}.", line, column)
+            }
+            PpmError::InvalidKernel(msg) => write!(f, "Invalid convolution kernel ({}).", msg),
+            PpmError::RegionOutOfBounds { x, y, width, height } => {
+                write!(f, "Region of {}x{} pixels at ({}, {}) is out of the image.", width, height, x, y)
+            }
+            PpmError::Cancelled => write!(f, "Processing was cancelled."),
+            PpmError::ProcessingFailed => write!(f, "Processing failed, a pixels operation panicked."),
+        }
+    }
+}
+
+impl error::Error for PpmError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            PpmError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PpmError {
+    fn from(e: io::Error) -> PpmError {
+        PpmError::Io(e)
+    }
+}
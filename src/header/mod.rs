@@ -0,0 +1,616 @@
+use std::io::prelude::*;
+
+use crate::error::PpmError;
+use crate::image::{Format, Kind};
+
+/*
+    The headers shared by every netpbm format (P1 to P6).
+    Bitmaps (P1/P4) have no max value header, their max value is always 1.
+    Comments found between the headers are kept, in order. For plain formats, the ones right after the last header are kept too.
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub kind: Kind,
+    pub format: Format,
+    pub width: usize,
+    pub height: usize,
+    pub max_value: usize,
+    pub comments: Vec<Comment>,
+}
+
+/*
+    Our Comment structure, a '#' comment line of the headers, like capture metadata.
+    text is what follows the '#' (and the single space after it, if any), without the line ending.
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Comment {
+    pub text: String,
+    // How many header values come before it: 1 after the magic number, 2 after the width, 3 after the height, 4 after the max value.
+    pub after: usize,
+    // 1-based line and column of its '#', None for comments which weren't read from a file.
+    pub position: Option<(usize, usize)>,
+}
+
+impl Comment {
+
+    // A new comment, written after the given number of header values.
+    pub fn new(text: &str, after: usize) -> Comment {
+        Comment {
+            text: text.to_string(),
+            after,
+            position: None,
+        }
+    }
+}
+
+// The plain formats allow lines up to 70 characters, our ASCII rows are wrapped at this length.
+pub(crate) const ASCII_LINE_LENGTH: usize = 70;
+
+// Largest width or height we accept, anything above is most likely a corrupted header.
+pub const MAX_DIMENSION: usize = 1 << 24;
+
+// Largest pixel count we accept (4 gigapixels), so both sides can't be huge at once.
+pub const MAX_PIXELS: u64 = 1 << 32;
+
+// In-memory readers reserve room for this many samples at most, the rest grows with the samples actually read.
+pub(crate) const PREALLOCATED_SAMPLES: usize = 1 << 20;
+
+// Whitespace as the netpbm formats define it: space, tab, LF, CR, vertical tab and form feed.
+const WHITESPACE: &[u8] = b" \t\n\r\x0b\x0c";
+
+/*
+    Our Tokenizer structure, it splits headers (and the pixels section of plain formats) into values, following the netpbm grammar.
+    Values are separated by whitespace, and a '#' starts a comment which runs to the end of its line, anywhere a value may end.
+    The single whitespace character ending a value is consumed with it, so after the last header the reader is at the pixels section.
+
+    It keeps track of the 1-based line and column it's at, for error messages. Bytes read through it (binary pixels) are counted too.
+*/
+pub(crate) struct Tokenizer<'a, R: BufRead> {
+    reader: &'a mut R,
+    token: Vec<u8>,
+    line: usize,
+    column: usize,
+    // Comments skipped so far, only while reading headers: plain pixels sections may hold many of them.
+    comments: Option<Vec<Comment>>,
+    comments_after: usize,
+    // Whether the last value was ended by a '#' rather than by whitespace.
+    before_comment: bool,
+    // How many bytes were read through it.
+    offset: usize,
+}
+
+// A value found by the Tokenizer, with the line and column of its first character.
+pub(crate) struct Token<'t> {
+    pub(crate) value: &'t [u8],
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+}
+
+impl Token<'_> {
+
+    // The value as an unsigned decimal number, None if it's anything else (a sign included) or doesn't fit.
+    pub(crate) fn number(&self) -> Option<usize> {
+        if self.value.is_empty() {
+            return None;
+        }
+
+        self.value.iter().try_fold(0usize, |number, byte| match byte {
+            b'0'..=b'9' => number.checked_mul(10)?.checked_add((byte - b'0') as usize),
+            _ => None,
+        })
+    }
+
+    pub(crate) fn text(&self) -> String {
+        String::from_utf8_lossy(self.value).into_owned()
+    }
+}
+
+impl<'a, R: BufRead> Tokenizer<'a, R> {
+
+    // Start tokenizing at the reader's current position, which is line 1, column 1.
+    pub(crate) fn new(reader: &'a mut R) -> Tokenizer<'a, R> {
+        Tokenizer::at(reader, (1, 1))
+    }
+
+    // Same as new(), when the reader is already somewhere in a file (after a previous image).
+    pub(crate) fn at(reader: &'a mut R, (line, column): (usize, usize)) -> Tokenizer<'a, R> {
+        Tokenizer {
+            reader,
+            token: vec![],
+            line,
+            column,
+            comments: None,
+            comments_after: 0,
+            before_comment: false,
+            offset: 0,
+        }
+    }
+
+    // Keep the comments skipped from now on, they come after the given number of header values.
+    pub(crate) fn keep_comments(&mut self, after: usize) {
+        self.comments.get_or_insert_with(Vec::new);
+        self.comments_after = after;
+    }
+
+    // Stop keeping comments, and get the ones kept so far.
+    pub(crate) fn take_comments(&mut self) -> Vec<Comment> {
+        self.comments.take().unwrap_or_default()
+    }
+
+    // The line and column of the next byte.
+    pub(crate) fn position(&self) -> (usize, usize) {
+        (self.line, self.column)
+    }
+
+    // How many bytes were read since the tokenizer was created, after the headers it's the pixels section offset.
+    pub(crate) fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.reader.consume(amount);
+        self.offset += amount;
+    }
+
+    /*
+        Skip whitespace and comments, of any length.
+        Returns true if there is something left to read, false at the end of the file.
+    */
+    pub(crate) fn skip(&mut self) -> Result<bool, PpmError> {
+
+        // The comment being skipped: the position of its '#' and its bytes.
+        let mut comment: Option<((usize, usize), Vec<u8>)> = None;
+
+        loop {
+            let buffer = self.reader.fill_buf()?;
+            if buffer.is_empty() {
+                end_comment(&mut self.comments, self.comments_after, comment);
+                return Ok(false);
+            }
+
+            let mut skipped = 0;
+            let mut found = false;
+
+            for &byte in buffer {
+                if let Some((_, text)) = comment.as_mut() {
+                    if byte == b'\n' || byte == b'\r' {
+                        end_comment(&mut self.comments, self.comments_after, comment.take());
+                    } else if self.comments.is_some() {
+                        text.push(byte);
+                    }
+                } else if byte == b'#' {
+                    comment = Some(((self.line, self.column), vec![]));
+                } else if !WHITESPACE.contains(&byte) {
+                    found = true;
+                    break;
+                }
+
+                skipped += 1;
+                advance(&mut self.line, &mut self.column, byte);
+            }
+
+            self.consume(skipped);
+
+            if found {
+                return Ok(true);
+            }
+        }
+    }
+
+    /*
+        Skip the comment right after the last value, if it was ended by one, up to and including its line ending.
+        Netpbm counts that comment as part of the whitespace after the last header, so its line ending is the one before a binary pixels section.
+    */
+    pub(crate) fn skip_comment_line(&mut self) -> Result<(), PpmError> {
+        if !self.before_comment {
+            return Ok(());
+        }
+        self.before_comment = false;
+
+        let position = self.position();
+        let mut text = vec![];
+
+        loop {
+            let buffer = self.reader.fill_buf()?;
+            if buffer.is_empty() {
+                break;
+            }
+
+            let length = buffer.iter().position(|byte| *byte == b'\n' || *byte == b'\r');
+            let consumed = length.map_or(buffer.len(), |length| length + 1);
+
+            text.extend_from_slice(&buffer[..length.unwrap_or(buffer.len())]);
+            for &byte in &buffer[..consumed] {
+                advance(&mut self.line, &mut self.column, byte);
+            }
+            self.consume(consumed);
+
+            if length.is_some() {
+                break;
+            }
+        }
+
+        // The '#' itself isn't part of the text.
+        text.remove(0);
+        end_comment(&mut self.comments, self.comments_after, Some((position, text)));
+        Ok(())
+    }
+
+    // The next value, None at the end of the file.
+    pub(crate) fn next_token(&mut self) -> Result<Option<Token<'_>>, PpmError> {
+        if !self.skip()? {
+            return Ok(None);
+        }
+
+        let (line, column) = self.position();
+        self.token.clear();
+        self.before_comment = false;
+
+        loop {
+            let buffer = self.reader.fill_buf()?;
+            if buffer.is_empty() {
+                break;
+            }
+
+            let length = buffer.iter().position(|byte| WHITESPACE.contains(byte) || *byte == b'#').unwrap_or(buffer.len());
+            self.token.extend_from_slice(&buffer[..length]);
+            self.column += length;
+
+            if length == buffer.len() {
+                self.consume(length);
+                continue;
+            }
+
+            // Consume the single whitespace ending the value, a comment is left for the next skip().
+            let delimiter = buffer[length];
+            if delimiter == b'#' {
+                self.consume(length);
+                self.before_comment = true;
+            } else {
+                self.consume(length + 1);
+                advance(&mut self.line, &mut self.column, delimiter);
+            }
+            break;
+        }
+
+        Ok(Some(Token {
+            value: &self.token,
+            line,
+            column,
+        }))
+    }
+
+    // The next character which is neither whitespace nor in a comment, with its line and column. Used by P1 bits, which need no separator.
+    pub(crate) fn next_char(&mut self) -> Result<Option<(u8, usize, usize)>, PpmError> {
+        if !self.skip()? {
+            return Ok(None);
+        }
+
+        let (line, column) = self.position();
+        let byte = self.reader.fill_buf()?[0];
+        self.consume(1);
+        self.column += 1;
+
+        Ok(Some((byte, line, column)))
+    }
+}
+
+// Binary pixels sections are read through the tokenizer too, so the position stays right for a next image.
+impl<R: BufRead> Read for Tokenizer<'_, R> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.reader.read(buffer)?;
+        self.offset += read;
+
+        for &byte in &buffer[..read] {
+            advance(&mut self.line, &mut self.column, byte);
+        }
+
+        Ok(read)
+    }
+}
+
+// The line and column reached from a position after the given bytes, to tell where a faulty binary sample is.
+pub(crate) fn position_after((mut line, mut column): (usize, usize), bytes: &[u8]) -> (usize, usize) {
+    for &byte in bytes {
+        advance(&mut line, &mut column, byte);
+    }
+
+    (line, column)
+}
+
+fn advance(line: &mut usize, column: &mut usize, byte: u8) {
+    if byte == b'\n' {
+        *line += 1;
+        *column = 1;
+    } else {
+        *column += 1;
+    }
+}
+
+// Keep a skipped comment, if comments are being kept.
+fn end_comment(comments: &mut Option<Vec<Comment>>, after: usize, comment: Option<((usize, usize), Vec<u8>)>) {
+    if let (Some(comments), Some((position, text))) = (comments.as_mut(), comment) {
+        let text = String::from_utf8_lossy(&text);
+
+        comments.push(Comment {
+            text: text.strip_prefix(' ').unwrap_or(&text).to_string(),
+            after,
+            position: Some(position),
+        });
+    }
+}
+
+/*
+    read_header(reader)
+
+    Parse every header of a netpbm file, starting at the reader's current position.
+    The reader is left at the pixels section offset.
+
+    Will return Result with Err if the magic number is unknown or a header is not a valid number.
+*/
+pub fn read_header<R: BufRead>(reader: &mut R) -> Result<Header, PpmError> {
+    read_header_tokens(&mut Tokenizer::new(reader))
+}
+
+// Same as read_header(), when the pixels section is read through the same tokenizer. Errors tell where the faulty header is.
+pub(crate) fn read_header_tokens<R: BufRead>(tokens: &mut Tokenizer<R>) -> Result<Header, PpmError> {
+
+    tokens.keep_comments(0);
+
+    let magic_number = next_header(tokens)?.text();
+    let (kind, format) = match Kind::from_magic_number(&magic_number) {
+        Some(parsed) => parsed,
+        None => return Err(PpmError::BadMagic(magic_number)),
+    };
+
+    // Get width and height headers, in this order.
+    tokens.keep_comments(1);
+    let width = parse_dimension(tokens)?;
+    tokens.keep_comments(2);
+    let height = parse_dimension(tokens)?;
+    check_dimensions(width, height, 3)?;
+
+    // Get max value, bitmaps don't have one.
+    tokens.keep_comments(3);
+    let max_value = match kind {
+        Kind::Bitmap => 1,
+        _ => {
+            let token = next_header(tokens)?;
+            match token.number() {
+                Some(parsed) => parsed,
+                None => {
+                    return Err(PpmError::BadHeader(format!(
+                        "max value {} is not a number, at line {}, column {}",
+                        token.text(),
+                        token.line,
+                        token.column
+                    )))
+                }
+            }
+        }
+    };
+
+    // Any value the spec allows is fine, samples are stored on 1 byte up to 255 and on 2 bytes above.
+    if !(1..=65535).contains(&max_value) {
+        return Err(PpmError::UnsupportedMaxval(max_value));
+    }
+
+    // Plain pixels sections may start with comments too, binary ones start right away, or after a comment ending the last header.
+    tokens.keep_comments(header_values(kind));
+    match format {
+        Format::Ascii => {
+            tokens.skip()?;
+        }
+        Format::Binary => tokens.skip_comment_line()?,
+    }
+
+    Ok(Header {
+        kind,
+        format,
+        width,
+        height,
+        max_value,
+        comments: tokens.take_comments(),
+    })
+}
+
+/*
+    Refuse dimensions above MAX_DIMENSION, more than MAX_PIXELS pixels, and images whose pixels section (with 2 bytes samples) can't be addressed.
+    Readers can then compute offsets and sizes without overflowing.
+*/
+pub(crate) fn check_dimensions(width: usize, height: usize, samples_per_pixel: usize) -> Result<(), PpmError> {
+    let bytes = width.checked_mul(height).and_then(|pixels| pixels.checked_mul(samples_per_pixel * 2));
+
+    match bytes {
+        Some(bytes)
+            if width <= MAX_DIMENSION
+                && height <= MAX_DIMENSION
+                && (width as u64) * (height as u64) <= MAX_PIXELS
+                && bytes <= isize::MAX as usize =>
+        {
+            Ok(())
+        }
+        _ => Err(PpmError::ImageTooLarge { width, height }),
+    }
+}
+
+// How many values the headers hold, the magic number included.
+fn header_values(kind: Kind) -> usize {
+    match kind {
+        Kind::Bitmap => 3,
+        _ => 4,
+    }
+}
+
+/*
+    write_header(writer, kind, format, (width, height), max_value, comments)
+
+    Write the headers of a netpbm image: the magic number, the dimensions and the max value (except for bitmaps).
+    Each comment is written right after the header value it followed (its after field), on its own line, so a read image gets its comments back in place.
+    Header values are on their own lines, except the width and height which share one when no comment comes between them.
+
+    The pixels section of binary formats starts right after the line ending of a comment following the last value,
+    so there's room for a single one there: comments following the last value are joined into it.
+*/
+pub(crate) fn write_header<W: Write>(
+    writer: &mut W,
+    kind: Kind,
+    format: Format,
+    (width, height): (usize, usize),
+    max_value: usize,
+    comments: &[Comment],
+) -> Result<(), PpmError> {
+
+    let mut values = vec![kind.magic_number(format).to_string(), width.to_string(), height.to_string()];
+    if kind != Kind::Bitmap {
+        values.push(max_value.to_string());
+    }
+
+    for (index, value) in values.iter().enumerate() {
+        let after = index + 1;
+        let last = after == values.len();
+
+        // Comments can't come before the magic number, and none can follow the last value.
+        let following: Vec<&Comment> = comments.iter().filter(|comment| comment.after.clamp(1, values.len()) == after).collect();
+
+        write!(writer, "{}", value)?;
+
+        if last && format == Format::Binary {
+            if !following.is_empty() {
+                let texts: Vec<String> = following.iter().map(|comment| comment.text.replace(['\n', '\r'], " ")).collect();
+                write!(writer, "# {}", texts.join(" "))?;
+            }
+            writeln!(writer)?;
+            continue;
+        }
+
+        if following.is_empty() && after == 2 {
+            write!(writer, " ")?;
+            continue;
+        }
+
+        writeln!(writer)?;
+        for comment in following {
+            // A comment can't span several lines, each of its lines is a comment of its own.
+            for text in comment.text.split(['\n', '\r']) {
+                match text {
+                    "" => writeln!(writer, "#")?,
+                    _ => writeln!(writer, "# {}", text)?,
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn next_header<'t, R: BufRead>(tokens: &'t mut Tokenizer<R>) -> Result<Token<'t>, PpmError> {
+    let (line, column) = tokens.position();
+
+    match tokens.next_token()? {
+        Some(token) => Ok(token),
+        None => Err(PpmError::BadHeader(format!("unexpected end of headers at line {}, column {}", line, column))),
+    }
+}
+
+fn parse_dimension<R: BufRead>(tokens: &mut Tokenizer<R>) -> Result<usize, PpmError> {
+    let token = next_header(tokens)?;
+
+    match token.number() {
+        Some(parsed) => Ok(parsed),
+        None => Err(PpmError::BadDimension(format!("{} at line {}, column {}", token.text(), token.line, token.column))),
+    }
+}
+
+// Module for testing
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::BufReader;
+
+    // Bytes split over two readers and read through a tiny buffer: not seekable, and values or comments span several reads.
+    fn pipe<'a>(first: &'a [u8], second: &'a [u8]) -> impl BufRead + 'a {
+        BufReader::with_capacity(7, first.chain(second))
+    }
+
+    #[test]
+    fn test_long_comment() {
+        let comment = "x".repeat(100_000);
+        let input = format!("P6\n# {}\n3 2 255\n", comment);
+
+        let header = read_header(&mut pipe(input.as_bytes(), b"\x01\x02")).unwrap();
+        assert_eq!((3, 2, 255), (header.width, header.height, header.max_value));
+        assert_eq!(1, header.comments.len());
+        assert_eq!(comment, header.comments[0].text);
+        assert_eq!(Some((2, 1)), header.comments[0].position);
+    }
+
+    #[test]
+    fn test_many_comments() {
+        let input = format!("P5 4{}\n3\n15\n", "\n# comment".repeat(100_000));
+
+        let mut reader = pipe(input.as_bytes(), b"\x07");
+        let header = read_header(&mut reader).unwrap();
+        assert_eq!((4, 3, 15), (header.width, header.height, header.max_value));
+        assert_eq!(100_000, header.comments.len());
+        assert!(header.comments.iter().all(|comment| comment.after == 2 && comment.text == "comment"));
+        assert_eq!(Some((100_001, 1)), header.comments.last().unwrap().position);
+
+        // The reader is left right at the pixels section.
+        let mut pixels = vec![];
+        reader.read_to_end(&mut pixels).unwrap();
+        assert_eq!(vec![7], pixels);
+    }
+
+    #[test]
+    fn test_comments_written_in_place() {
+        let comments: Vec<Comment> = (1..=4).map(|after| Comment::new(&format!("after {}", after), after)).collect();
+
+        for (kind, format, expected) in [
+            (Kind::Greymap, Format::Ascii, "P2\n# after 1\n3\n# after 2\n2\n# after 3\n15\n# after 4\n"),
+            (Kind::Pixmap, Format::Binary, "P6\n# after 1\n3\n# after 2\n2\n# after 3\n15# after 4\n"),
+            (Kind::Bitmap, Format::Binary, "P4\n# after 1\n3\n# after 2\n2# after 3 after 4\n"),
+        ] {
+            let mut bytes = vec![];
+            write_header(&mut bytes, kind, format, (3, 2), 15, &comments).unwrap();
+            assert_eq!(expected, String::from_utf8(bytes.clone()).unwrap());
+
+            // Read back, every comment follows the same value as before.
+            let header = read_header(&mut &bytes[..]).unwrap();
+            let afters: Vec<usize> = header.comments.iter().map(|comment| comment.after).collect();
+            let expected_afters = match kind {
+                Kind::Bitmap => vec![1, 2, 3],
+                _ => vec![1, 2, 3, 4],
+            };
+            assert_eq!(expected_afters, afters);
+        }
+    }
+
+    #[test]
+    fn test_split_values() {
+        let header = read_header(&mut pipe(b"P3 12", b"34 5#six\n65535 ")).unwrap();
+        assert_eq!((1234, 5, 65535), (header.width, header.height, header.max_value));
+        assert_eq!("six", header.comments[0].text);
+    }
+
+    #[test]
+    fn test_absurd_dimensions() {
+        let too_wide = format!("P6 {} 1 255\n", MAX_DIMENSION + 1);
+        match read_header(&mut too_wide.as_bytes()) {
+            Err(PpmError::ImageTooLarge { width, height }) => assert_eq!((MAX_DIMENSION + 1, 1), (width, height)),
+            _ => panic!("expected an image too large error"),
+        }
+
+        let too_many_pixels = format!("P2 {} {} 255\n", MAX_DIMENSION, MAX_DIMENSION);
+        match read_header(&mut too_many_pixels.as_bytes()) {
+            Err(PpmError::ImageTooLarge { width, height }) => assert_eq!((MAX_DIMENSION, MAX_DIMENSION), (width, height)),
+            _ => panic!("expected an image too large error"),
+        }
+
+        match read_header(&mut "P1 99999999999999999999999 1\n".as_bytes()) {
+            Err(PpmError::BadDimension(value)) => assert!(value.starts_with("99999999999999999999999 at line 1")),
+            _ => panic!("expected a bad dimension error"),
+        }
+    }
+}
@@ -0,0 +1,645 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use crate::error::PpmError;
+use crate::header::{read_header_tokens, Comment, Header, Tokenizer};
+use crate::p3;
+use crate::p6;
+use crate::pbm;
+use crate::pgm;
+
+// A single RGB pixel, whatever encoding it was read from.
+// Samples are u16 so that 16 bits images (max value above 255) are kept without losing precision.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Pixel {
+    pub red: u16,
+    pub green: u16,
+    pub blue: u16,
+}
+
+impl Pixel {
+
+    // Invert the pixel, relative to the image's max value.
+    pub fn invert(&mut self, max_value: u16) {
+        self.red = max_value.saturating_sub(self.red);
+        self.green = max_value.saturating_sub(self.green);
+        self.blue = max_value.saturating_sub(self.blue);
+    }
+
+    // Scale every sample from one max value to another, rounding to the nearest value.
+    pub fn rescale(&mut self, max_value: u16, new_max_value: u16) {
+        let rescale = |sample: u16| {
+            let scaled = (sample as u32 * new_max_value as u32 + max_value as u32 / 2) / max_value as u32;
+            scaled.min(new_max_value as u32) as u16
+        };
+
+        self.red = rescale(self.red);
+        self.green = rescale(self.green);
+        self.blue = rescale(self.blue);
+    }
+
+    // The grey level of the pixel, the average of its samples.
+    pub fn grey(self) -> u16 {
+        ((self.red as u32 + self.green as u32 + self.blue as u32) / 3) as u16
+    }
+
+    pub fn greyscale(&mut self) {
+        let grey = self.grey();
+        self.red = grey;
+        self.green = grey;
+        self.blue = grey;
+    }
+}
+
+// The two netpbm encodings: P1/P2/P3 store samples as ASCII decimals, P4/P5/P6 as raw bytes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Ascii,
+    Binary,
+}
+
+// What the samples stand for: black and white bitmaps (PBM), greymaps (PGM) or RGB pixmaps (PPM).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Bitmap,
+    Greymap,
+    Pixmap,
+}
+
+impl Kind {
+
+    pub fn magic_number(self, format: Format) -> &'static str {
+        match (self, format) {
+            (Kind::Bitmap, Format::Ascii) => "P1",
+            (Kind::Greymap, Format::Ascii) => "P2",
+            (Kind::Pixmap, Format::Ascii) => "P3",
+            (Kind::Bitmap, Format::Binary) => "P4",
+            (Kind::Greymap, Format::Binary) => "P5",
+            (Kind::Pixmap, Format::Binary) => "P6",
+        }
+    }
+
+    pub fn from_magic_number(magic_number: &str) -> Option<(Kind, Format)> {
+        match magic_number {
+            "P1" => Some((Kind::Bitmap, Format::Ascii)),
+            "P2" => Some((Kind::Greymap, Format::Ascii)),
+            "P3" => Some((Kind::Pixmap, Format::Ascii)),
+            "P4" => Some((Kind::Bitmap, Format::Binary)),
+            "P5" => Some((Kind::Greymap, Format::Binary)),
+            "P6" => Some((Kind::Pixmap, Format::Binary)),
+            _ => None,
+        }
+    }
+}
+
+/*
+    Our in-memory Image structure.
+    Every pixel is loaded, so it is meant for images that fit in RAM.
+    For huge binary files, use p6::BinaryImage which streams the pixels instead.
+
+    The format field only remembers where the image came from, so that save() writes it back the same way.
+    Bitmaps and greymaps are kept as RGB pixels too, the kind field tells how they are written:
+    greymaps use the grey level of each pixel, and bitmaps have a max value of 1 where 0 is black.
+*/
+pub struct Image {
+    pub(crate) pixels: Vec<Pixel>,
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    pub(crate) max_value: u16,
+    pub(crate) format: Format,
+    pub(crate) kind: Kind,
+    pub(crate) comments: Vec<Comment>,
+}
+
+impl Image {
+
+    pub fn new(width: usize, height: usize, format: Format) -> Image {
+        Image {
+            pixels: vec![Pixel::default(); width * height],
+            width,
+            height,
+            max_value: 255,
+            format,
+            kind: Kind::Pixmap,
+            comments: vec![],
+        }
+    }
+
+    // Build an image from its parsed headers and pixels.
+    pub(crate) fn with_pixels(header: &Header, pixels: Vec<Pixel>) -> Image {
+        Image {
+            pixels,
+            width: header.width,
+            height: header.height,
+            max_value: header.max_value as u16,
+            format: header.format,
+            kind: header.kind,
+            comments: header.comments.clone(),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn max_value(&self) -> u16 {
+        self.max_value
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    pub fn pixels(&self) -> &[Pixel] {
+        &self.pixels
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [Pixel] {
+        &mut self.pixels
+    }
+
+    // The comments of its headers, in order, they are written back when saving.
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+
+    pub fn comments_mut(&mut self) -> &mut Vec<Comment> {
+        &mut self.comments
+    }
+
+    //  adds a comment line after the existing ones (after the magic number if there are none).
+    pub fn add_comment(&mut self, text: &str) {
+        let after = self.comments.last().map_or(1, |comment| comment.after);
+        self.comments.push(Comment::new(text, after));
+    }
+
+    //  saves Image into a file, using the encoding it was read from.
+    pub fn save(&self, filename: &Path) -> Result<(), PpmError> {
+        self.save_as(filename, self.format)
+    }
+
+    //  saves Image into a file, using the given encoding.
+    pub fn save_as(&self, filename: &Path, format: Format) -> Result<(), PpmError> {
+        let mut writer = BufWriter::new(File::create(filename)?);
+
+        self.write_to(&mut writer, format)?;
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    //  writes Image to any writer (stdout, a socket, a Vec<u8>...) using the given encoding, the writer is not flushed.
+    pub fn write_to<W: Write>(&self, writer: &mut W, format: Format) -> Result<(), PpmError> {
+        match (self.kind, format) {
+            (Kind::Bitmap, _) => pbm::write_image(self, format, writer),
+            (Kind::Greymap, _) => pgm::write_image(self, format, writer),
+            (Kind::Pixmap, Format::Ascii) => p3::write_image(self, writer),
+            (Kind::Pixmap, Format::Binary) => p6::write_image(self, writer),
+        }
+    }
+
+    //    function that inverts image colors
+    pub fn invert(&mut self) {
+        for pixel in self.pixels.iter_mut() {
+            pixel.invert(self.max_value);
+        }
+    }
+
+    /*
+        rescale_maxval(new_max_value)
+
+        Convert the image to another depth, for example from a 4 bits (15) scanner image to 8 bits (255).
+        Every sample is scaled proportionally and rounded to the nearest value.
+
+        Will return Result with Err if the new max value is 0.
+    */
+    pub fn rescale_maxval(&mut self, new_max_value: u16) -> Result<(), PpmError> {
+        if new_max_value == 0 {
+            return Err(PpmError::UnsupportedMaxval(0));
+        }
+
+        for pixel in self.pixels.iter_mut() {
+            pixel.rescale(self.max_value, new_max_value);
+        }
+
+        self.max_value = new_max_value;
+        Ok(())
+    }
+
+    //    function that makes image B&W based on a filter color
+    pub fn greyscale(&mut self) {
+        for pixel in self.pixels.iter_mut() {
+            pixel.greyscale();
+        }
+    }
+
+    //    function that reduces the image to a single channel greymap (PGM), saving it then writes P2 or P5.
+    pub fn to_greymap(&mut self) {
+        self.greyscale();
+        self.kind = Kind::Greymap;
+    }
+
+    //    function that promotes a bitmap or greymap to an RGB pixmap (PPM), pixels are left untouched.
+    pub fn to_pixmap(&mut self) {
+        self.kind = Kind::Pixmap;
+    }
+}
+
+/*
+    open(filename)
+
+    Load any netpbm file (P1 to P6) into an Image.
+    The kind and encoding are guessed from the magic number in the first two bytes of the file.
+
+    Will return Result with Err if the magic number is unknown or the file can't be read.
+*/
+pub fn open(filename: &Path) -> Result<Image, PpmError> {
+
+    let mut magic_number = [0; 2];
+    BufReader::new(File::open(filename)?).read_exact(&mut magic_number)?;
+
+    let magic_number = String::from_utf8_lossy(&magic_number);
+
+    match Kind::from_magic_number(&magic_number) {
+        Some((Kind::Bitmap, _)) => pbm::new_with_file(filename),
+        Some((Kind::Greymap, _)) => pgm::new_with_file(filename),
+        Some((Kind::Pixmap, Format::Ascii)) => p3::new_with_file(filename),
+        Some((Kind::Pixmap, Format::Binary)) => p6::new_with_file_bin(filename)?.load(),
+        None => Err(PpmError::BadMagic(magic_number.to_string())),
+    }
+}
+
+/*
+    decode(reader)
+
+    Load any netpbm image (P1 to P6) from a reader instead of a file, for example a Cursor over bytes already in memory.
+    The reader is never seeked, so pipes and sockets work too.
+
+    Will return Result with Err if the magic number is unknown or the image is invalid.
+*/
+pub fn decode<R: BufRead>(mut reader: R) -> Result<Image, PpmError> {
+    read_image(&mut Tokenizer::new(&mut reader))
+}
+
+/*
+    Read a single image (headers and pixels) of any netpbm kind, starting at the tokenizer's current position.
+    The reader is left right after the image's pixels section, where the next image may start.
+*/
+pub(crate) fn read_image<R: BufRead>(tokens: &mut Tokenizer<R>) -> Result<Image, PpmError> {
+
+    let header = read_header_tokens(tokens)?;
+
+    let pixels = match header.kind {
+        Kind::Bitmap => pbm::read_pixels(tokens, &header)?,
+        Kind::Greymap => pgm::read_pixels(tokens, &header)?,
+
+        // Pixmaps are greymap samples, three at a time.
+        Kind::Pixmap => {
+            let count = (header.width * header.height) * 3;
+            let samples = match header.format {
+                Format::Ascii => pgm::read_ascii_samples(tokens, count, header.max_value)?,
+                Format::Binary => pgm::read_binary_samples(tokens, count, header.max_value)?,
+            };
+
+            samples
+                .chunks_exact(3)
+                .map(|rgb| Pixel {
+                    red: rgb[0],
+                    green: rgb[1],
+                    blue: rgb[2],
+                })
+                .collect()
+        }
+    };
+
+    Ok(Image::with_pixels(&header, pixels))
+}
+
+/*
+    Our Images iterator, returned by open_all().
+    It yields every image of a multi-image file, each one with its own headers (and possibly a different kind or size).
+    Iteration stops after the first error.
+*/
+pub struct Images<R: BufRead = BufReader<File>> {
+    reader: R,
+    // Line and column the reader is at, so errors in any image tell where they are in the whole file.
+    position: (usize, usize),
+    failed: bool,
+}
+
+impl<R: BufRead> Iterator for Images<R> {
+    type Item = Result<Image, PpmError>;
+
+    fn next(&mut self) -> Option<Result<Image, PpmError>> {
+        if self.failed {
+            return None;
+        }
+
+        // Whitespace and comments may follow an image's pixels section.
+        let mut tokens = Tokenizer::at(&mut self.reader, self.position);
+        let image = match tokens.skip() {
+            Ok(false) => return None,
+            Ok(true) => read_image(&mut tokens),
+            Err(e) => Err(e),
+        };
+
+        self.position = tokens.position();
+
+        self.failed = image.is_err();
+        Some(image)
+    }
+}
+
+/*
+    open_all(filename)
+
+    Iterate over all the images concatenated in a netpbm file, as the spec allows.
+    Each image must start where the previous one's pixels section ends (on a new line for ASCII images).
+
+    Will return Result with Err if the file can't be opened, reading errors come with each image.
+*/
+pub fn open_all(filename: &Path) -> Result<Images, PpmError> {
+    Ok(decode_all(BufReader::new(File::open(filename)?)))
+}
+
+// Same as open_all(), for any buffered reader.
+pub fn decode_all<R: BufRead>(reader: R) -> Images<R> {
+    Images { reader, position: (1, 1), failed: false }
+}
+
+// Module for testing
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn get_p3_test_file_path() -> &'static Path {
+        Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p3/test/test.ppm"))
+    }
+
+    fn get_p6_test_file_path() -> &'static Path {
+        Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/alaska.ppm"))
+    }
+
+    #[test]
+    fn test_open_sniffs_format() {
+        let ascii = open(get_p3_test_file_path()).unwrap();
+        assert_eq!(Format::Ascii, ascii.format());
+        assert_eq!((3, 2), (ascii.width(), ascii.height()));
+        assert_eq!(6, ascii.pixels().len());
+
+        let binary = open(get_p6_test_file_path()).unwrap();
+        assert_eq!(Format::Binary, binary.format());
+        assert_eq!((512, 512), (binary.width(), binary.height()));
+        assert_eq!(512 * 512, binary.pixels().len());
+    }
+
+    #[test]
+    fn test_save_as_other_format() {
+        let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p3/test/binary.test.ppm"));
+
+        let mut image = open(get_p3_test_file_path()).unwrap();
+        image.invert();
+        image.save_as(out_file_path, Format::Binary).unwrap();
+
+        let saved = open(out_file_path).unwrap();
+        assert_eq!(Format::Binary, saved.format());
+        assert_eq!((image.width(), image.height()), (saved.width(), saved.height()));
+        assert_eq!(image.pixels(), saved.pixels());
+    }
+
+    #[test]
+    fn test_rescale_maxval() {
+        let mut image = open(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p3/test/legacy.ppm"))).unwrap();
+        assert_eq!(15, image.max_value());
+
+        image.rescale_maxval(255).unwrap();
+        assert_eq!(255, image.max_value());
+        assert_eq!(Pixel { red: 255, green: 0, blue: 119 }, image.pixels()[0]);
+        assert_eq!(Pixel { red: 17, green: 136, blue: 238 }, image.pixels()[1]);
+
+        image.rescale_maxval(15).unwrap();
+        assert_eq!(Pixel { red: 15, green: 0, blue: 7 }, image.pixels()[0]);
+        assert_eq!(Pixel { red: 1, green: 8, blue: 14 }, image.pixels()[1]);
+
+        assert!(image.rescale_maxval(0).is_err());
+    }
+
+    #[test]
+    fn test_invert_respects_maxval() {
+        let mut image = open(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p3/test/legacy.ppm"))).unwrap();
+
+        image.invert();
+        assert_eq!(Pixel { red: 0, green: 15, blue: 8 }, image.pixels()[0]);
+        assert_eq!(Pixel { red: 14, green: 7, blue: 1 }, image.pixels()[1]);
+    }
+
+    #[test]
+    fn test_16_bits_round_trip() {
+        let in_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p3/test/deep.ppm"));
+        let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p3/test/deep.binary.test.ppm"));
+
+        let image = open(in_file_path).unwrap();
+        assert_eq!(65535, image.max_value());
+        assert_eq!(Pixel { red: 300, green: 40000, blue: 7 }, image.pixels()[1]);
+
+        image.save_as(out_file_path, Format::Binary).unwrap();
+
+        let saved = open(out_file_path).unwrap();
+        assert_eq!(65535, saved.max_value());
+        assert_eq!(image.pixels(), saved.pixels());
+    }
+
+    #[test]
+    fn test_decode_and_write_to_memory() {
+        use std::io::Cursor;
+
+        let image = decode(Cursor::new(&b"P2 3 1 15\n0 7 15\n"[..])).unwrap();
+        assert_eq!((Kind::Greymap, 15), (image.kind(), image.max_value()));
+
+        let mut bytes: Vec<u8> = vec![];
+        image.write_to(&mut bytes, Format::Binary).unwrap();
+        assert_eq!(b"P5\n3 1\n15\n\x00\x07\x0f".to_vec(), bytes);
+
+        let images: Vec<Image> = decode_all(Cursor::new(bytes)).map(|image| image.unwrap()).collect();
+        assert_eq!(1, images.len());
+        assert_eq!(image.pixels(), images[0].pixels());
+    }
+
+    #[test]
+    fn test_decode_from_pipe() {
+        // Slices and chains of readers can't seek, just like stdin or a pipe.
+        let first: &[u8] = b"P2 2 1 15\n# first\n3 4\nP5 ";
+        let second: &[u8] = b"1 1\n# second\n255\n\x07";
+        let images: Vec<Image> = decode_all(BufReader::with_capacity(5, first.chain(second))).map(|image| image.unwrap()).collect();
+
+        assert_eq!(2, images.len());
+        assert_eq!((Kind::Greymap, 4), (images[0].kind(), images[0].pixels()[1].red));
+        assert_eq!("second", images[1].comments()[0].text);
+        assert_eq!(7, images[1].pixels()[0].red);
+
+        assert_eq!(6, pgm::new_with_reader(&b"P5 1 1 255\n\x06"[..]).unwrap().pixels()[0].red);
+        assert_eq!(1, pbm::new_with_reader(&b"P1 1 1\n0"[..]).unwrap().pixels()[0].red);
+
+        // A header announcing a huge image doesn't allocate it before its pixels are read.
+        let huge = format!("P5 {} {} 255\n\x01", 1 << 16, 1 << 16);
+        match decode(huge.as_bytes()) {
+            Err(PpmError::TruncatedPixelData { expected, got }) => assert_eq!((1 << 32, 1), (expected, got)),
+            _ => panic!("expected a truncated pixel data error"),
+        }
+    }
+
+    #[test]
+    fn test_open_refuses_absurd_dimensions() {
+        let file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/huge.test.ppm"));
+        std::fs::write(file_path, b"P6 16777216 16777216 255\n\x01\x02\x03").unwrap();
+
+        match open(file_path) {
+            Err(PpmError::ImageTooLarge { width, height }) => assert_eq!((1 << 24, 1 << 24), (width, height)),
+            _ => panic!("expected an image too large error"),
+        }
+
+        // Within the limits, a lying header is only found out while reading, nothing huge is allocated beforehand.
+        std::fs::write(file_path, b"P6 65536 65536 255\n\x01\x02\x03").unwrap();
+
+        match open(file_path) {
+            Err(PpmError::TruncatedPixelData { expected, got }) => assert_eq!((3 << 32, 3), (expected, got)),
+            _ => panic!("expected a truncated pixel data error"),
+        }
+    }
+
+    #[test]
+    fn test_samples_above_maxval() {
+        let file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/above_maxval.test.ppm"));
+
+        // The second pixel's green sample is above 100, after a pixels section byte which happens to be a line feed.
+        let input = b"P6\n2 1\n100\n\x01\x0a\x03\x04\xc8\x06";
+        std::fs::write(file_path, input).unwrap();
+
+        let invalid = |result: Result<Pixel, PpmError>| match result {
+            Err(PpmError::InvalidSample { line, column }) => (line, column),
+            _ => panic!("expected an invalid sample error"),
+        };
+
+        // Whichever way the file is read, it is refused at the same position.
+        assert_eq!((5, 3), invalid(decode(&input[..]).map(|image| image.pixels[0])));
+        assert_eq!((5, 3), invalid(open(file_path).map(|image| image.pixels[0])));
+
+        let mut img = p6::new_with_file_bin(file_path).unwrap();
+        assert_eq!((5, 3), invalid(img.get_pixel(1, 0)));
+        assert_eq!((5, 3), invalid(img.read_region(0, 0, 2, 1).map(|image| image.pixels[0])));
+        assert_eq!((5, 3), invalid(img.rows().unwrap().next().unwrap().map(|row| row.pixels[0])));
+        assert_eq!((5, 3), invalid(crate::mmap::new_with_file_mmap(file_path).unwrap().pixel(1, 0)));
+
+        // The first pixel is fine on its own.
+        assert_eq!(Pixel { red: 1, green: 10, blue: 3 }, img.get_pixel(0, 0).unwrap());
+    }
+
+    #[test]
+    fn test_comments_round_trip() {
+        use std::io::Cursor;
+
+        let image = open(get_p3_test_file_path()).unwrap();
+        let texts: Vec<&str> = image.comments().iter().map(|comment| comment.text.as_str()).collect();
+        assert_eq!(5, texts.len());
+        assert_eq!("The part above is the header", texts[0]);
+        assert_eq!("\"3 2\" is the width and height of the image in pixels", texts[2]);
+        assert_eq!(Some((4, 1)), image.comments()[0].position);
+        assert!(image.comments().iter().all(|comment| comment.after == 4));
+
+        // Comments stay after the max value, in the same order.
+        let mut ascii = vec![];
+        image.write_to(&mut ascii, Format::Ascii).unwrap();
+        assert!(ascii.starts_with(b"P3\n3 2\n255\n# The part above is the header\n"));
+
+        let back = decode(Cursor::new(&ascii)).unwrap();
+        let back_texts: Vec<&str> = back.comments().iter().map(|comment| comment.text.as_str()).collect();
+        assert_eq!(texts, back_texts);
+        assert!(back.comments().iter().all(|comment| comment.after == 4));
+        assert_eq!(image.pixels(), back.pixels());
+
+        // Binary pixels start right after the line ending of a single comment, so the ones following the max value are joined.
+        let mut binary = vec![];
+        image.write_to(&mut binary, Format::Binary).unwrap();
+        assert!(binary.starts_with(b"P6\n3 2\n255# The part above is the header \"P3\" means"));
+
+        let decoded = decode(Cursor::new(&binary)).unwrap();
+        assert_eq!(vec![Comment { text: texts.join(" "), after: 4, position: Some((3, 4)) }], decoded.comments().to_vec());
+        assert_eq!(image.pixels(), decoded.pixels());
+    }
+
+    #[test]
+    fn test_edit_comments() {
+        use std::io::Cursor;
+
+        let mut image = decode(Cursor::new(&b"P2\n# scanner A\n2 # width\n1\n#created today\n15\n0 15\n"[..])).unwrap();
+        let positions: Vec<_> = image.comments().iter().map(|comment| (comment.after, comment.position)).collect();
+        assert_eq!(vec![(1, Some((2, 1))), (2, Some((3, 3))), (3, Some((5, 1)))], positions);
+        assert_eq!("created today", image.comments()[2].text);
+
+        image.comments_mut()[0].text = "scanner B".to_string();
+        image.comments_mut().remove(1);
+        image.add_comment("exposure 1/60\nISO 400");
+
+        let mut bytes = vec![];
+        image.write_to(&mut bytes, Format::Ascii).unwrap();
+        assert_eq!(
+            "P2\n# scanner B\n2 1\n# created today\n# exposure 1/60\n# ISO 400\n15\n0\n15\n",
+            String::from_utf8(bytes).unwrap()
+        );
+
+        let mut blank = Image::new(1, 1, Format::Binary);
+        blank.add_comment("generated");
+
+        let mut bytes = vec![];
+        blank.write_to(&mut bytes, Format::Binary).unwrap();
+        assert_eq!(b"P6\n# generated\n1 1\n255\n\x00\x00\x00".to_vec(), bytes);
+    }
+
+    #[test]
+    fn test_open_all() {
+        let images: Vec<Image> = open_all(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/image/test/frames.ppm")))
+            .unwrap()
+            .map(|image| image.unwrap())
+            .collect();
+
+        assert_eq!(3, images.len());
+        assert_eq!((2, 1, Kind::Pixmap), (images[0].width(), images[0].height(), images[0].kind()));
+        assert_eq!(Pixel { red: 4, green: 5, blue: 6 }, images[0].pixels()[1]);
+        assert_eq!(Kind::Greymap, images[1].kind());
+        assert_eq!(Pixel { red: 200, green: 200, blue: 200 }, images[1].pixels()[0]);
+        assert_eq!(65535, images[2].max_value());
+        assert_eq!(Pixel { red: 65534, green: 1, blue: 32768 }, images[2].pixels()[0]);
+    }
+
+    #[test]
+    fn test_open_all_ascii() {
+        let mut images = open_all(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/image/test/frames.ascii.ppm"))).unwrap();
+
+        assert_eq!(Pixel { red: 10, green: 20, blue: 30 }, images.next().unwrap().unwrap().pixels()[0]);
+
+        let greymap = images.next().unwrap().unwrap();
+        assert_eq!((Kind::Greymap, 15), (greymap.kind(), greymap.max_value()));
+        assert_eq!(Pixel { red: 4, green: 4, blue: 4 }, greymap.pixels()[1]);
+
+        assert!(images.next().is_none());
+    }
+
+    #[test]
+    fn test_open_all_stops_after_error() {
+        let mut images = open_all(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/truncated.ppm"))).unwrap();
+
+        assert!(images.next().unwrap().is_err());
+        assert!(images.next().is_none());
+    }
+}
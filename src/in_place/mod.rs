@@ -0,0 +1,356 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, BufReader, ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::error::PpmError;
+use crate::p6::{new_with_reader_bin, process_pixels, Layout, Pipeline};
+use crate::processor::Processor;
+use crate::progress::Monitor;
+
+// Journal files start with this line, followed by the chunk's offset and length (big-endian u64) and its original bytes.
+const JOURNAL_MAGIC: &[u8] = b"PPMJOURNAL1\n";
+
+/*
+    process_in_place(filename, process)
+
+    Process (transform) a P6 image right inside its file, the pixels section is rewritten chunk by chunk and the headers are left untouched.
+    No second file is needed, but an interrupted run leaves a half-processed image: see process_in_place_journaled() for a crash-safe version.
+
+    Return a Result with nothing if everything went smooth.
+*/
+pub fn process_in_place<P: Into<Pipeline>>(filename: &Path, process: P) -> Result<(), PpmError> {
+    process_in_place_with(&Processor::default(), &Monitor::new(), filename, process)
+}
+
+/*
+    process_in_place_with(processor, monitor, filename, process)
+
+    Same as process_in_place(), on an existing Processor's workers, with progress reported to the monitor.
+    Once the monitor is cancelled the run stops with PpmError::Cancelled, leaving a half-processed image like any interrupted run.
+*/
+pub fn process_in_place_with<P: Into<Pipeline>>(processor: &Processor, monitor: &Monitor, filename: &Path, process: P) -> Result<(), PpmError> {
+    let mut writer = OpenOptions::new().write(true).open(filename)?;
+
+    run(processor, monitor, filename, &mut writer, 0, process.into())?;
+
+    writer.sync_all()?;
+    Ok(())
+}
+
+/*
+    process_in_place_journaled(filename, process)
+
+    Same as process_in_place(), but before a chunk is overwritten its original bytes are saved to a journal (filename.journal).
+    If the run is interrupted, call this function again with the same process: the chunk being written is restored from the journal,
+    then processing resumes where it stopped. The journal is removed once the whole image is processed.
+
+    Every chunk is synced to disk before the next one is written, which makes this mode slower.
+
+    Return a Result with nothing if everything went smooth.
+*/
+pub fn process_in_place_journaled<P: Into<Pipeline>>(filename: &Path, process: P) -> Result<(), PpmError> {
+    process_in_place_journaled_with(&Processor::default(), &Monitor::new(), filename, process)
+}
+
+/*
+    process_in_place_journaled_with(processor, monitor, filename, process)
+
+    Same as process_in_place_journaled(), on an existing Processor's workers, with progress reported to the monitor.
+    A cancelled run keeps its journal, calling this function again resumes it.
+*/
+pub fn process_in_place_journaled_with<P: Into<Pipeline>>(
+    processor: &Processor,
+    monitor: &Monitor,
+    filename: &Path,
+    process: P,
+) -> Result<(), PpmError> {
+    let journal = journal_path(filename);
+
+    let mut file = OpenOptions::new().read(true).write(true).open(filename)?;
+
+    // A journal left by an interrupted run, put back the chunk it was writing and resume from there.
+    let resume_offset = match read_journal(&journal)? {
+        Some((offset, original)) => {
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&original)?;
+            file.sync_data()?;
+            offset
+        }
+        None => 0,
+    };
+
+    let mut writer = JournaledWriter {
+        file,
+        journal: journal.clone(),
+        position: 0,
+    };
+
+    run(processor, monitor, filename, &mut writer, resume_offset, process.into())?;
+
+    writer.file.sync_all()?;
+    fs::remove_file(&journal)?;
+    sync_parent(&journal);
+
+    Ok(())
+}
+
+// Where the journal of an image file is kept.
+pub fn journal_path(filename: &Path) -> PathBuf {
+    let mut path = filename.as_os_str().to_owned();
+    path.push(".journal");
+    PathBuf::from(path)
+}
+
+/*
+    Stream the pixels section from the file to the writer, which writes back to the same file.
+    The writer is always behind the reader, so every chunk is read before being overwritten.
+    Nothing is written before skip_until, which is an offset in the file.
+*/
+fn run<W: Seek + Write + Send>(
+    processor: &Processor,
+    monitor: &Monitor,
+    filename: &Path,
+    writer: &mut W,
+    skip_until: u64,
+    pipeline: Pipeline,
+) -> Result<(), PpmError> {
+    let mut img = new_with_reader_bin(BufReader::new(File::open(filename)?))?;
+    img.reader.seek(SeekFrom::Start(img.pixels_offset as u64))?;
+
+    writer.seek(SeekFrom::Start(img.pixels_offset as u64))?;
+    let mut writer = SkippingWriter {
+        inner: writer,
+        position: img.pixels_offset as u64,
+        skip_until,
+    };
+
+    process_pixels(
+        processor,
+        monitor,
+        &mut img.reader,
+        &mut writer,
+        (img.width, img.height),
+        0..img.height,
+        Layout::rgb(img.rgb_max_value),
+        img.rgb_max_value as u32,
+        pipeline,
+    )
+}
+
+// A writer which drops everything before an offset, the part already processed by an interrupted run.
+struct SkippingWriter<'a, W: Seek + Write> {
+    inner: &'a mut W,
+    position: u64,
+    skip_until: u64,
+}
+
+impl<W: Seek + Write> Write for SkippingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let end = self.position + buf.len() as u64;
+
+        if end > self.skip_until {
+            let skipped = self.skip_until.saturating_sub(self.position) as usize;
+            self.inner.seek(SeekFrom::Start(self.position + skipped as u64))?;
+            self.inner.write_all(&buf[skipped..])?;
+        }
+
+        self.position = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/*
+    A writer which journals the bytes it's about to overwrite.
+    The journal is written to a temporary file then renamed, so it is always complete: either the previous chunk or this one.
+*/
+struct JournaledWriter {
+    file: File,
+    journal: PathBuf,
+    position: u64,
+}
+
+impl Write for JournaledWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+
+        // Save the original bytes first.
+        let mut original = vec![0; buf.len()];
+        self.file.seek(SeekFrom::Start(self.position))?;
+        self.file.read_exact(&mut original)?;
+        write_journal(&self.journal, self.position, &original)?;
+
+        // Then overwrite them, and make sure they reached the disk before the journal moves on.
+        self.file.seek(SeekFrom::Start(self.position))?;
+        self.file.write_all(buf)?;
+        self.file.sync_data()?;
+
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+impl Seek for JournaledWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = self.file.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+fn write_journal(journal: &Path, offset: u64, original: &[u8]) -> io::Result<()> {
+    let mut temporary = journal.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+
+    let mut file = File::create(&temporary)?;
+    file.write_all(JOURNAL_MAGIC)?;
+    file.write_all(&offset.to_be_bytes())?;
+    file.write_all(&(original.len() as u64).to_be_bytes())?;
+    file.write_all(original)?;
+    file.sync_all()?;
+
+    fs::rename(&temporary, journal)?;
+    sync_parent(journal);
+
+    Ok(())
+}
+
+// Read a journal, None if there's none.
+fn read_journal(journal: &Path) -> Result<Option<(u64, Vec<u8>)>, PpmError> {
+    let bytes = match fs::read(journal) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let corrupt = || PpmError::Io(io::Error::new(ErrorKind::InvalidData, format!("corrupt journal {}", journal.display())));
+
+    let header_length = JOURNAL_MAGIC.len() + 16;
+    if bytes.len() < header_length || !bytes.starts_with(JOURNAL_MAGIC) {
+        return Err(corrupt());
+    }
+
+    let number = |start: usize| {
+        let mut be_bytes = [0; 8];
+        be_bytes.copy_from_slice(&bytes[start..start + 8]);
+        u64::from_be_bytes(be_bytes)
+    };
+    let offset = number(JOURNAL_MAGIC.len());
+    let length = number(JOURNAL_MAGIC.len() + 8) as usize;
+
+    if bytes.len() != header_length + length {
+        return Err(corrupt());
+    }
+
+    Ok(Some((offset, bytes[header_length..].to_vec())))
+}
+
+// Make a rename or a removal durable, directories can't be synced on every platform so failures are ignored.
+fn sync_parent(path: &Path) {
+    if let Some(Ok(directory)) = path.parent().map(File::open) {
+        let _ = directory.sync_all();
+    }
+}
+
+// Module for testing
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::p6::{new_with_file_bin, ImageProcess};
+
+    fn copy_test_file(name: &str, copy: &str) -> PathBuf {
+        let path = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/")).join(copy);
+        fs::copy(PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/")).join(name), &path).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_in_place_matches_output() {
+        for (copy, journaled) in [("alaska.in_place.test.ppm", false), ("alaska.journaled.test.ppm", true)] {
+            let path = copy_test_file("alaska.ppm", copy);
+
+            let mut expected: Vec<u8> = vec![];
+            new_with_file_bin(&path).unwrap().process_and_write(&mut expected, ImageProcess::Invert).unwrap();
+
+            if journaled {
+                process_in_place_journaled(&path, ImageProcess::Invert).unwrap();
+            } else {
+                process_in_place(&path, ImageProcess::Invert).unwrap();
+            }
+
+            assert_eq!(expected, fs::read(&path).unwrap());
+            assert!(!journal_path(&path).exists());
+        }
+    }
+
+    #[test]
+    fn test_resume_after_crash() {
+        let path = copy_test_file("deep.ppm", "deep.crash.test.ppm");
+        let original = fs::read(&path).unwrap();
+
+        let mut expected: Vec<u8> = vec![];
+        new_with_file_bin(&path).unwrap().process_and_write(&mut expected, ImageProcess::Invert).unwrap();
+
+        // A run which died while writing the second row (4 pixels of 6 bytes): the first row is done, the second one is garbage.
+        let pixels_offset = new_with_file_bin(&path).unwrap().pixels_offset;
+        let second_row = pixels_offset + 24;
+
+        let mut crashed = expected[..second_row].to_vec();
+        crashed.extend_from_slice(&[0xAB; 24]);
+        fs::write(&path, crashed).unwrap();
+        write_journal(&journal_path(&path), second_row as u64, &original[second_row..]).unwrap();
+
+        process_in_place_journaled(&path, ImageProcess::Invert).unwrap();
+
+        assert_eq!(expected, fs::read(&path).unwrap());
+        assert!(!journal_path(&path).exists());
+    }
+
+    #[test]
+    fn test_resume_after_cancellation() {
+        // Larger than the chunks in flight at once, so the reader has to wait for the writer and sees the cancellation.
+        let path = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/large.cancelled.test.ppm"));
+        let mut input = b"P6\n2048 2048\n255\n".to_vec();
+        input.extend((0..2048 * 2048 * 3).map(|i| (i % 251) as u8));
+        fs::write(&path, input).unwrap();
+
+        let mut expected: Vec<u8> = vec![];
+        new_with_file_bin(&path).unwrap().process_and_write(&mut expected, ImageProcess::Invert).unwrap();
+
+        // Cancelled once the first chunk is written.
+        let token = crate::progress::CancellationToken::new();
+        let monitor = {
+            let token = token.clone();
+            Monitor::new().on_progress(move |_| token.cancel())
+        }
+        .with_cancellation(token);
+
+        match process_in_place_journaled_with(&Processor::new(64), &monitor, &path, ImageProcess::Invert) {
+            Err(PpmError::Cancelled) => assert!(journal_path(&path).exists()),
+            _ => panic!("expected a cancelled process"),
+        }
+        assert_ne!(expected, fs::read(&path).unwrap());
+
+        process_in_place_journaled(&path, ImageProcess::Invert).unwrap();
+
+        assert_eq!(expected, fs::read(&path).unwrap());
+        assert!(!journal_path(&path).exists());
+    }
+
+    #[test]
+    fn test_corrupt_journal() {
+        let path = copy_test_file("legacy.ppm", "legacy.corrupt.test.ppm");
+        fs::write(journal_path(&path), b"PPMJOURNAL1\n\x00").unwrap();
+
+        assert!(process_in_place_journaled(&path, ImageProcess::Invert).is_err());
+        fs::remove_file(journal_path(&path)).unwrap();
+    }
+}
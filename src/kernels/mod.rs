@@ -0,0 +1,398 @@
+use crate::p6::bytes_per_sample;
+
+extern crate test;
+
+/*
+    Vectorised versions of our per-pixel processes, they work straight on the raw interleaved bytes of a P6 pixels section (r, g, b, r, g, b...).
+    On x86_64 the best instruction set is picked at runtime: AVX2 or SSE2 for invert, SSSE3 for greyscale.
+    Anything else, including 16 bits greyscale, goes through the scalar versions.
+
+    Results are bit-exact with invert_binary_pixel() and greyscale_binary_pixel().
+*/
+
+// Our vectorised processes, see ImageProcess for what they do.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum Kernel {
+    Invert,
+    Greyscale,
+}
+
+impl Kernel {
+
+    pub(crate) fn apply(self, pixels: &mut [u8], rgb_max_value: u32) {
+        match self {
+            Kernel::Invert => invert(pixels, rgb_max_value),
+            Kernel::Greyscale => greyscale(pixels, rgb_max_value),
+        }
+    }
+}
+
+/*
+    invert(pixels, rgb_max_value)
+
+    Invert every sample of a P6 pixels section, 16 bits samples are big-endian.
+    pixels must hold whole samples, like invert_binary_pixel() out of range samples are clamped to 0.
+*/
+pub fn invert(pixels: &mut [u8], rgb_max_value: u32) {
+    match bytes_per_sample(rgb_max_value as usize) {
+        1 => invert_8(pixels, rgb_max_value as u8),
+        _ => invert_16(pixels, rgb_max_value as u16),
+    }
+}
+
+/*
+    greyscale(pixels, rgb_max_value)
+
+    Turn every pixel of a P6 pixels section into greyscale, the max value only tells the samples' size.
+    pixels must hold whole pixels.
+*/
+pub fn greyscale(pixels: &mut [u8], rgb_max_value: u32) {
+    match bytes_per_sample(rgb_max_value as usize) {
+        1 => greyscale_8(pixels),
+        _ => greyscale_16_scalar(pixels),
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn invert_8(samples: &mut [u8], max: u8) {
+    // Safe, the instruction sets are detected first and SSE2 is part of x86_64.
+    if is_x86_feature_detected!("avx2") {
+        unsafe { x86::invert_8_avx2(samples, max) }
+    } else {
+        unsafe { x86::invert_8_sse2(samples, max) }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn invert_8(samples: &mut [u8], max: u8) {
+    invert_8_scalar(samples, max)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn invert_16(samples: &mut [u8], max: u16) {
+    // Safe, the instruction sets are detected first and SSE2 is part of x86_64.
+    if is_x86_feature_detected!("avx2") {
+        unsafe { x86::invert_16_avx2(samples, max) }
+    } else {
+        unsafe { x86::invert_16_sse2(samples, max) }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn invert_16(samples: &mut [u8], max: u16) {
+    invert_16_scalar(samples, max)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn greyscale_8(pixels: &mut [u8]) {
+    // Safe, the instruction set is detected first.
+    if is_x86_feature_detected!("ssse3") {
+        unsafe { x86::greyscale_8_ssse3(pixels) }
+    } else {
+        greyscale_8_scalar(pixels)
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn greyscale_8(pixels: &mut [u8]) {
+    greyscale_8_scalar(pixels)
+}
+
+/* Scalar versions, used as fallbacks and for what's left after the last full vector. */
+
+fn invert_8_scalar(samples: &mut [u8], max: u8) {
+    for sample in samples.iter_mut() {
+        *sample = max.saturating_sub(*sample);
+    }
+}
+
+fn invert_16_scalar(samples: &mut [u8], max: u16) {
+    for sample in samples.chunks_exact_mut(2) {
+        let inverted = max.saturating_sub(u16::from_be_bytes([sample[0], sample[1]]));
+        sample.copy_from_slice(&inverted.to_be_bytes());
+    }
+}
+
+fn greyscale_8_scalar(pixels: &mut [u8]) {
+    for pixel in pixels.chunks_exact_mut(3) {
+        let grey = ((pixel[0] as u32 + pixel[1] as u32 + pixel[2] as u32) / 3) as u8;
+        pixel.fill(grey);
+    }
+}
+
+fn greyscale_16_scalar(pixels: &mut [u8]) {
+    for pixel in pixels.chunks_exact_mut(6) {
+        let sample = |i: usize| u16::from_be_bytes([pixel[i * 2], pixel[i * 2 + 1]]) as u32;
+        let grey = (((sample(0) + sample(1) + sample(2)) / 3) as u16).to_be_bytes();
+
+        for sample in pixel.chunks_exact_mut(2) {
+            sample.copy_from_slice(&grey);
+        }
+    }
+}
+
+/*
+    x86_64 versions, each one must only be called once its instruction set is detected.
+    Loads and stores are unaligned, so any slice works.
+*/
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+
+    use super::*;
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn invert_8_sse2(samples: &mut [u8], max: u8) {
+        let maxs = _mm_set1_epi8(max as i8);
+
+        let mut blocks = samples.chunks_exact_mut(16);
+        for block in &mut blocks {
+            let pointer = block.as_mut_ptr() as *mut __m128i;
+            _mm_storeu_si128(pointer, _mm_subs_epu8(maxs, _mm_loadu_si128(pointer)));
+        }
+
+        invert_8_scalar(blocks.into_remainder(), max);
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn invert_8_avx2(samples: &mut [u8], max: u8) {
+        let maxs = _mm256_set1_epi8(max as i8);
+
+        let mut blocks = samples.chunks_exact_mut(32);
+        for block in &mut blocks {
+            let pointer = block.as_mut_ptr() as *mut __m256i;
+            _mm256_storeu_si256(pointer, _mm256_subs_epu8(maxs, _mm256_loadu_si256(pointer)));
+        }
+
+        invert_8_scalar(blocks.into_remainder(), max);
+    }
+
+    // Samples are big-endian, their bytes are swapped before and after the subtraction.
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn invert_16_sse2(samples: &mut [u8], max: u16) {
+        let maxs = _mm_set1_epi16(max as i16);
+        let swap = |v: __m128i| _mm_or_si128(_mm_slli_epi16(v, 8), _mm_srli_epi16(v, 8));
+
+        let mut blocks = samples.chunks_exact_mut(16);
+        for block in &mut blocks {
+            let pointer = block.as_mut_ptr() as *mut __m128i;
+            let inverted = _mm_subs_epu16(maxs, swap(_mm_loadu_si128(pointer)));
+            _mm_storeu_si128(pointer, swap(inverted));
+        }
+
+        invert_16_scalar(blocks.into_remainder(), max);
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn invert_16_avx2(samples: &mut [u8], max: u16) {
+        let maxs = _mm256_set1_epi16(max as i16);
+        let swap = |v: __m256i| _mm256_or_si256(_mm256_slli_epi16(v, 8), _mm256_srli_epi16(v, 8));
+
+        let mut blocks = samples.chunks_exact_mut(32);
+        for block in &mut blocks {
+            let pointer = block.as_mut_ptr() as *mut __m256i;
+            let inverted = _mm256_subs_epu16(maxs, swap(_mm256_loadu_si256(pointer)));
+            _mm256_storeu_si256(pointer, swap(inverted));
+        }
+
+        invert_16_scalar(blocks.into_remainder(), max);
+    }
+
+    /*
+        8 pixels (24 bytes) at a time, read as two overlapping 16 bytes vectors: bytes 0 to 15 and bytes 8 to 23.
+        Shuffles spread the red, green and blue samples into 16 bits lanes, one per pixel, which are summed.
+        Dividing by 3 is a multiplication: (sum * 0xAAAB) >> 17 is exact for any sum of three 8 bits samples.
+    */
+    #[target_feature(enable = "ssse3")]
+    pub(super) unsafe fn greyscale_8_ssse3(pixels: &mut [u8]) {
+        let low = [
+            _mm_setr_epi8(0, -1, 3, -1, 6, -1, 9, -1, 12, -1, -1, -1, -1, -1, -1, -1),
+            _mm_setr_epi8(1, -1, 4, -1, 7, -1, 10, -1, 13, -1, -1, -1, -1, -1, -1, -1),
+            _mm_setr_epi8(2, -1, 5, -1, 8, -1, 11, -1, 14, -1, -1, -1, -1, -1, -1, -1),
+        ];
+        let high = [
+            _mm_setr_epi8(-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, 7, -1, 10, -1, 13, -1),
+            _mm_setr_epi8(-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, 8, -1, 11, -1, 14, -1),
+            _mm_setr_epi8(-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, 9, -1, 12, -1, 15, -1),
+        ];
+        let third = _mm_set1_epi16(0xAAABu16 as i16);
+        let spread_low = _mm_setr_epi8(0, 0, 0, 1, 1, 1, 2, 2, 2, 3, 3, 3, 4, 4, 4, 5);
+        let spread_high = _mm_setr_epi8(5, 5, 6, 6, 6, 7, 7, 7, -1, -1, -1, -1, -1, -1, -1, -1);
+
+        let mut blocks = pixels.chunks_exact_mut(24);
+        for block in &mut blocks {
+            let first = _mm_loadu_si128(block.as_ptr() as *const __m128i);
+            let second = _mm_loadu_si128(block[8..].as_ptr() as *const __m128i);
+
+            let mut sums = _mm_setzero_si128();
+            for i in 0..3 {
+                sums = _mm_add_epi16(sums, _mm_shuffle_epi8(first, low[i]));
+                sums = _mm_add_epi16(sums, _mm_shuffle_epi8(second, high[i]));
+            }
+
+            let greys = _mm_srli_epi16(_mm_mulhi_epu16(sums, third), 1);
+            let greys = _mm_packus_epi16(greys, greys);
+
+            _mm_storeu_si128(block.as_mut_ptr() as *mut __m128i, _mm_shuffle_epi8(greys, spread_low));
+            _mm_storel_epi64(block[16..].as_mut_ptr() as *mut __m128i, _mm_shuffle_epi8(greys, spread_high));
+        }
+
+        greyscale_8_scalar(blocks.into_remainder());
+    }
+}
+
+// Module for testing
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::p6::{greyscale_binary_pixel, invert_binary_pixel, BinaryPixel, Layout};
+
+    // Deterministic noise, with lengths which leave a remainder after the last full vector.
+    fn noise(length: usize) -> Vec<u8> {
+        let mut state: u32 = 0x9E37_79B9;
+        (0..length)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    // The reference, our per-pixel processes.
+    fn per_pixel(pixels: &[u8], rgb_max_value: u32, process: fn(&mut BinaryPixel, u32)) -> Vec<u8> {
+        let layout = Layout::rgb(rgb_max_value as usize);
+        let mut pixels = pixels.to_vec();
+
+        for bytes in pixels.chunks_exact_mut(layout.pixel_bytes()) {
+            let mut pixel = BinaryPixel::read(bytes, layout);
+            process(&mut pixel, rgb_max_value);
+            pixel.write(bytes, layout);
+        }
+
+        pixels
+    }
+
+    #[test]
+    fn test_matches_per_pixel_processes() {
+        for rgb_max_value in [255, 100, 15, 1, 65535, 1000, 256] {
+            let pixel_bytes = Layout::rgb(rgb_max_value as usize).pixel_bytes();
+
+            for pixels_count in [0, 1, 5, 8, 13, 64, 1001] {
+                let pixels = noise(pixels_count * pixel_bytes);
+
+                let mut inverted = pixels.clone();
+                invert(&mut inverted, rgb_max_value);
+                assert_eq!(per_pixel(&pixels, rgb_max_value, invert_binary_pixel), inverted);
+
+                let mut grey = pixels.clone();
+                greyscale(&mut grey, rgb_max_value);
+                assert_eq!(per_pixel(&pixels, rgb_max_value, greyscale_binary_pixel), grey);
+            }
+        }
+    }
+
+    // Every instruction set the CPU has, not only the one picked at runtime.
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_x86_matches_scalar() {
+        for length in [0, 3, 16, 24, 47, 96, 3001] {
+            let samples = noise(length * 2);
+
+            for max in [255, 200, 0] {
+                let mut expected = samples.clone();
+                invert_8_scalar(&mut expected, max);
+
+                let mut sse2 = samples.clone();
+                unsafe { x86::invert_8_sse2(&mut sse2, max) };
+                assert_eq!(expected, sse2);
+
+                if is_x86_feature_detected!("avx2") {
+                    let mut avx2 = samples.clone();
+                    unsafe { x86::invert_8_avx2(&mut avx2, max) };
+                    assert_eq!(expected, avx2);
+                }
+            }
+
+            for max in [65535, 40000, 256] {
+                let mut expected = samples.clone();
+                invert_16_scalar(&mut expected, max);
+
+                let mut sse2 = samples.clone();
+                unsafe { x86::invert_16_sse2(&mut sse2, max) };
+                assert_eq!(expected, sse2);
+
+                if is_x86_feature_detected!("avx2") {
+                    let mut avx2 = samples.clone();
+                    unsafe { x86::invert_16_avx2(&mut avx2, max) };
+                    assert_eq!(expected, avx2);
+                }
+            }
+
+            if is_x86_feature_detected!("ssse3") {
+                let pixels = noise(length * 3);
+
+                let mut expected = pixels.clone();
+                greyscale_8_scalar(&mut expected);
+
+                let mut ssse3 = pixels.clone();
+                unsafe { x86::greyscale_8_ssse3(&mut ssse3) };
+                assert_eq!(expected, ssse3);
+            }
+        }
+    }
+
+    // Every possible pixel whose samples sum to the same value, for each sum.
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_greyscale_every_sum() {
+        if !is_x86_feature_detected!("ssse3") {
+            return;
+        }
+
+        let pixels: Vec<u8> = (0..=765u32).flat_map(|sum| [(sum / 3) as u8, ((sum + 1) / 3) as u8, sum.div_ceil(3) as u8]).collect();
+
+        let mut expected = pixels.clone();
+        greyscale_8_scalar(&mut expected);
+
+        let mut ssse3 = pixels;
+        unsafe { x86::greyscale_8_ssse3(&mut ssse3) };
+        assert_eq!(expected, ssse3);
+    }
+}
+
+// Module for benchmarks
+#[cfg(test)]
+mod bench {
+
+    use super::*;
+    use test::Bencher;
+
+    fn pixels() -> Vec<u8> {
+        (0..512 * 512 * 3).map(|i| i as u8).collect()
+    }
+
+    #[bench]
+    fn bench_invert_kernel(b: &mut Bencher) {
+        let mut pixels = pixels();
+        b.iter(|| invert(&mut pixels, 255));
+    }
+
+    #[bench]
+    fn bench_invert_scalar(b: &mut Bencher) {
+        let mut pixels = pixels();
+        b.iter(|| invert_8_scalar(&mut pixels, 255));
+    }
+
+    #[bench]
+    fn bench_greyscale_kernel(b: &mut Bencher) {
+        let mut pixels = pixels();
+        b.iter(|| greyscale(&mut pixels, 255));
+    }
+
+    #[bench]
+    fn bench_greyscale_scalar(b: &mut Bencher) {
+        let mut pixels = pixels();
+        b.iter(|| greyscale_8_scalar(&mut pixels));
+    }
+}
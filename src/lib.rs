@@ -1,3 +1,16 @@
 #![feature(test)]
+pub mod convert;
+pub mod error;
+pub mod header;
+pub mod image;
+pub mod in_place;
+pub mod kernels;
+pub mod mmap;
+pub mod neighborhood;
 pub mod p6;
 pub mod p3;
+pub mod p7;
+pub mod pbm;
+pub mod pgm;
+pub mod processor;
+pub mod progress;
@@ -0,0 +1,432 @@
+use std::fs::{File, OpenOptions};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Mutex;
+
+use memmap2::{Mmap, MmapMut};
+
+use crate::error::PpmError;
+use crate::header::{position_after, read_header_tokens, write_header, Comment, Tokenizer};
+use crate::image::{Format, Kind, Pixel};
+use crate::p6::{bytes_per_sample, decode_samples, discard_on_error, transform_chunk, BinaryPixel, Layout, Pipeline, PIXELS_BUFFER_BYTES_LENGTH};
+use crate::processor::Processor;
+use crate::progress::{Monitor, Progress};
+
+/*
+    Our MappedImage structure, the memory-mapped counterpart of p6::BinaryImage.
+    The whole file is mapped, the OS loads pages when they are touched, so any pixel can be read (or written) without copies.
+    A read-only mapping (Mmap) is used by default, open it with new_with_file_mmap_mut() to modify it in place.
+
+    The file must not be modified by anyone else while it is mapped.
+*/
+pub struct MappedImage<M: Deref<Target = [u8]> = Mmap> {
+    map: M,
+    pub pixels_offset: usize,
+    pub height: usize,
+    pub width: usize,
+    pub rgb_max_value: usize,
+    // Comments of the headers, written back by process_and_output().
+    pub comments: Vec<Comment>,
+    // Line and column the pixels section starts at, for error messages.
+    position: (usize, usize),
+}
+
+impl<M: Deref<Target = [u8]>> MappedImage<M> {
+
+    // The raw pixels section, 3 samples per pixel, each one 1 byte (8 bits samples) or 2 big-endian bytes (16 bits samples).
+    pub fn pixels_bytes(&self) -> &[u8] {
+        &self.map[self.pixels_offset..self.pixels_offset + self.width * self.height * self.layout().pixel_bytes()]
+    }
+
+    /*
+        pixel(x, y)
+
+        Get the pixel at (x, y), just like BinaryImage::get_pixel().
+
+        Will return Result with Err if (x, y) is outside of the image, or if a sample is above the max value.
+    */
+    pub fn pixel(&self, x: usize, y: usize) -> Result<Pixel, PpmError> {
+        if x >= self.width || y >= self.height {
+            return Err(PpmError::RegionOutOfBounds { x, y, width: 1, height: 1 });
+        }
+
+        let pixel_bytes = self.layout().pixel_bytes();
+        let start = (y * self.width + x) * pixel_bytes;
+        let pixels = self.pixels_bytes();
+
+        let mut samples = [0; 3];
+        let position = || Ok(position_after(self.position, &pixels[..start]));
+        decode_samples(&pixels[start..start + pixel_bytes], self.rgb_max_value, position, &mut samples)?;
+
+        Ok(Pixel {
+            red: samples[0],
+            green: samples[1],
+            blue: samples[2],
+        })
+    }
+
+    /*
+        process_and_output(filename, process)
+
+        Process (transform) image and output it to a file, just like BinaryImage::process_and_output().
+        The output file is created with its final size and mapped too, so pixels go straight from one mapping to the other.
+
+        Return a Result with nothing if everything went smooth.
+    */
+    pub fn process_and_output<P: Into<Pipeline>>(&self, filename: &Path, process: P) -> Result<(), PpmError> {
+        self.process_and_output_with(&Processor::default(), filename, process)
+    }
+
+    // Same as process_and_output(), with as many workers as the processor has threads.
+    pub fn process_and_output_with<P: Into<Pipeline>>(&self, processor: &Processor, filename: &Path, process: P) -> Result<(), PpmError> {
+        self.process_and_output_monitored(processor, filename, process, &Monitor::new())
+    }
+
+    /*
+        process_and_output_monitored(processor, filename, process, monitor)
+
+        Same as process_and_output_with(), but progress is reported to the monitor and the process can be cancelled through it.
+        A cancelled or failed process leaves no partial output file behind.
+
+        Return a Result with nothing if everything went smooth, or PpmError::Cancelled.
+    */
+    pub fn process_and_output_monitored<P: Into<Pipeline>>(
+        &self,
+        processor: &Processor,
+        filename: &Path,
+        process: P,
+        monitor: &Monitor,
+    ) -> Result<(), PpmError> {
+        let result = self.write_output(processor, filename, process.into(), monitor);
+        discard_on_error(filename, result)
+    }
+
+    // Create the output file with its final size, map it and write the processed image into it.
+    fn write_output(&self, processor: &Processor, filename: &Path, pipeline: Pipeline, monitor: &Monitor) -> Result<(), PpmError> {
+
+        let mut headers = vec![];
+        write_header(&mut headers, Kind::Pixmap, Format::Binary, (self.width, self.height), self.rgb_max_value, &self.comments)?;
+
+        let pixels = self.pixels_bytes();
+
+        let out_file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(filename)?;
+        out_file.set_len((headers.len() + pixels.len()) as u64)?;
+
+        // Safe as long as nobody else touches the output file while we write it.
+        let mut out_map = unsafe { MmapMut::map_mut(&out_file)? };
+        out_map[..headers.len()].copy_from_slice(&headers);
+
+        let (width, height, layout, max) = (self.width, self.height, self.layout(), self.rgb_max_value as u32);
+        transform_mapped(processor, monitor, &mut out_map[headers.len()..], Some(pixels), width, height, layout, max, &pipeline)?;
+
+        out_map.flush()?;
+        Ok(())
+    }
+
+    fn layout(&self) -> Layout {
+        Layout::rgb(self.rgb_max_value)
+    }
+}
+
+impl MappedImage<MmapMut> {
+
+    // The raw pixels section, writes go straight to the file.
+    pub fn pixels_bytes_mut(&mut self) -> &mut [u8] {
+        let length = self.width * self.height * self.layout().pixel_bytes();
+        &mut self.map[self.pixels_offset..self.pixels_offset + length]
+    }
+
+    // Set the pixel at (x, y), samples above the max value are clamped. Return false if it's outside of the image.
+    pub fn set_pixel(&mut self, x: usize, y: usize, pixel: Pixel) -> bool {
+        if x >= self.width || y >= self.height {
+            return false;
+        }
+
+        let layout = self.layout();
+        let max = self.rgb_max_value as u32;
+        let start = (y * self.width + x) * layout.pixel_bytes();
+
+        let pixel = BinaryPixel {
+            r: (pixel.red as u32).min(max),
+            g: (pixel.green as u32).min(max),
+            b: (pixel.blue as u32).min(max),
+        };
+        pixel.write(&mut self.pixels_bytes_mut()[start..start + layout.pixel_bytes()], layout);
+
+        true
+    }
+
+    // Transform every pixel right inside the mapped file, then flush it to disk.
+    pub fn transform<P: Into<Pipeline>>(&mut self, process: P) -> Result<(), PpmError> {
+        self.transform_with(&Processor::default(), process)
+    }
+
+    // Same as transform(), with as many workers as the processor has threads.
+    pub fn transform_with<P: Into<Pipeline>>(&mut self, processor: &Processor, process: P) -> Result<(), PpmError> {
+        let (width, height, layout, max) = (self.width, self.height, self.layout(), self.rgb_max_value as u32);
+
+        transform_mapped(processor, &Monitor::new(), self.pixels_bytes_mut(), None, width, height, layout, max, &process.into())?;
+        self.flush()
+    }
+
+    // Write pending changes to disk, they are written by the OS at some point anyway.
+    pub fn flush(&self) -> Result<(), PpmError> {
+        Ok(self.map.flush()?)
+    }
+}
+
+/*
+    Transform a mapped pixels section in place, chunk by chunk of complete rows, with one worker per processor thread.
+    The pool's workers only take jobs owning what they touch, so chunks are handed to scoped workers instead, borrowed straight from the mapping.
+    When given, the source pixels are copied first, each worker copying its own chunks.
+
+    Progress is reported to the monitor after each chunk, workers stop taking chunks once it is cancelled.
+
+    Return a Result with nothing if everything went smooth, PpmError::Cancelled, or PpmError::ProcessingFailed if a chunk's transformation panicked.
+*/
+#[allow(clippy::too_many_arguments)]
+fn transform_mapped(
+    processor: &Processor,
+    monitor: &Monitor,
+    pixels: &mut [u8],
+    source: Option<&[u8]>,
+    width: usize,
+    height: usize,
+    layout: Layout,
+    rgb_max_value: u32,
+    pipeline: &Pipeline,
+) -> Result<(), PpmError> {
+    let row_bytes = width * layout.pixel_bytes();
+    if row_bytes == 0 {
+        return Ok(());
+    }
+
+    let chunk_rows = (PIXELS_BUFFER_BYTES_LENGTH / processor.threads() / row_bytes).max(1);
+    let total = pixels.len() as u64;
+
+    // Chunks left to transform, and how many bytes are done. Progress is reported under the lock, so it never goes backwards.
+    let chunks = Mutex::new((pixels.chunks_mut(chunk_rows * row_bytes).enumerate(), 0));
+
+    let work = || -> Result<(), PpmError> {
+        loop {
+            let (index, chunk) = match chunks.lock().map_err(|_| PpmError::ProcessingFailed)?.0.next() {
+                Some(chunk) => chunk,
+                None => return Ok(()),
+            };
+
+            if monitor.is_cancelled() {
+                return Err(PpmError::Cancelled);
+            }
+
+            let first_row = index * chunk_rows;
+            if let Some(source) = source {
+                chunk.copy_from_slice(&source[first_row * row_bytes..first_row * row_bytes + chunk.len()]);
+            }
+            transform_chunk(chunk, width, first_row, &(0..height), layout, rgb_max_value, pipeline);
+
+            let mut chunks = chunks.lock().map_err(|_| PpmError::ProcessingFailed)?;
+            chunks.1 += chunk.len() as u64;
+            monitor.report(Progress { done: chunks.1, total });
+        }
+    };
+
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..processor.threads()).map(|_| scope.spawn(work)).collect();
+
+        // Every worker is joined by us, an unjoined panicking one would take the caller down.
+        let joined: Vec<_> = workers.into_iter().map(|worker| worker.join()).collect();
+        joined.into_iter().try_for_each(|joined| joined.unwrap_or(Err(PpmError::ProcessingFailed)))
+    })
+}
+
+/*
+    new_with_file_mmap(filename)
+
+    Map a P6 file read-only, this struct could then be used with process_and_output(out_filename, process).
+
+    Will return Result with Err if the file is not a P6 image or its pixels section is truncated.
+*/
+pub fn new_with_file_mmap(filename: &Path) -> Result<MappedImage, PpmError> {
+
+    // Safe as long as nobody else modifies the file while it is mapped.
+    let map = unsafe { Mmap::map(&File::open(filename)?)? };
+    from_map(map)
+}
+
+// Same as new_with_file_mmap(), but the mapping is writable: every change goes to the file.
+pub fn new_with_file_mmap_mut(filename: &Path) -> Result<MappedImage<MmapMut>, PpmError> {
+
+    let file = OpenOptions::new().read(true).write(true).open(filename)?;
+
+    // Safe as long as nobody else touches the file while it is mapped.
+    let map = unsafe { MmapMut::map_mut(&file)? };
+    from_map(map)
+}
+
+fn from_map<M: Deref<Target = [u8]>>(map: M) -> Result<MappedImage<M>, PpmError> {
+
+    // Headers are parsed straight from the mapping.
+    let mut reader = &map[..];
+    let mut tokens = Tokenizer::new(&mut reader);
+    let header = read_header_tokens(&mut tokens)?;
+
+    if (header.kind, header.format) != (Kind::Pixmap, Format::Binary) {
+        return Err(PpmError::BadMagic(header.kind.magic_number(header.format).to_string()));
+    }
+
+    let (pixels_offset, position) = (tokens.offset(), tokens.position());
+
+    // The whole pixels section must be there, since it's accessed directly.
+    let samples = header.width * header.height * 3;
+    let available = map.len() - pixels_offset;
+    if available < samples * bytes_per_sample(header.max_value) {
+        return Err(PpmError::TruncatedPixelData { expected: samples, got: available / bytes_per_sample(header.max_value) });
+    }
+
+    Ok(MappedImage {
+        map,
+        pixels_offset,
+        height: header.height,
+        width: header.width,
+        rgb_max_value: header.max_value,
+        comments: header.comments,
+        position,
+    })
+}
+
+// Module for testing
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::p6::{new_with_file_bin, ImageProcess};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn get_test_file_path() -> &'static Path {
+        Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/alaska.ppm"))
+    }
+
+    #[test]
+    fn test_matches_buffered_processing() {
+        let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/alaska.mmap.test.ppm"));
+
+        let pipeline = Pipeline::new().then(ImageProcess::Greyscale).then(ImageProcess::Invert);
+
+        let mapped = new_with_file_mmap(get_test_file_path()).unwrap();
+        mapped.process_and_output(out_file_path, pipeline.clone()).unwrap();
+
+        let mut expected: Vec<u8> = vec![];
+        new_with_file_bin(get_test_file_path()).unwrap().process_and_write(&mut expected, pipeline).unwrap();
+
+        assert_eq!(expected, std::fs::read(out_file_path).unwrap());
+    }
+
+    #[test]
+    fn test_shared_processor() {
+        let file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/alaska.mmap.shared.test.ppm"));
+
+        // Many workers, so the image is split into many chunks, more than can be in flight at once.
+        let processor = Processor::new(64);
+
+        new_with_file_mmap(get_test_file_path()).unwrap().process_and_output_with(&processor, file_path, ImageProcess::Greyscale).unwrap();
+
+        let mut mapped = new_with_file_mmap_mut(file_path).unwrap();
+        mapped.transform_with(&processor, ImageProcess::Invert).unwrap();
+        drop(mapped);
+
+        let mut expected: Vec<u8> = vec![];
+        let pipeline = Pipeline::new().then(ImageProcess::Greyscale).then(ImageProcess::Invert);
+        new_with_file_bin(get_test_file_path()).unwrap().process_and_write(&mut expected, pipeline).unwrap();
+
+        assert_eq!(expected, std::fs::read(file_path).unwrap());
+    }
+
+    #[test]
+    fn test_cancelled_mid_run() {
+        let in_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/large.mmap.test.ppm"));
+        let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/large.mmap.cancelled.test.ppm"));
+
+        // Several chunks, even for a single worker.
+        let mut input = b"P6\n2048 2048\n255\n".to_vec();
+        input.extend((0..2048 * 2048 * 3).map(|i| (i % 251) as u8));
+        std::fs::write(in_file_path, input).unwrap();
+
+        // A single worker, cancelled once its first chunk is done.
+        let token = crate::progress::CancellationToken::new();
+        let reports = Arc::new(AtomicUsize::new(0));
+        let monitor = {
+            let (token, reports) = (token.clone(), Arc::clone(&reports));
+            Monitor::new().on_progress(move |_| {
+                reports.fetch_add(1, Ordering::SeqCst);
+                token.cancel();
+            })
+        }
+        .with_cancellation(token);
+
+        let mapped = new_with_file_mmap(in_file_path).unwrap();
+        match mapped.process_and_output_monitored(&Processor::new(1), out_file_path, ImageProcess::Invert, &monitor) {
+            Err(PpmError::Cancelled) => assert!(!out_file_path.exists()),
+            _ => panic!("expected a cancelled process"),
+        }
+        assert_eq!(1, reports.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_random_access() {
+        let image = new_with_file_bin(get_test_file_path()).unwrap().load().unwrap();
+        let mapped = new_with_file_mmap(get_test_file_path()).unwrap();
+
+        assert_eq!(image.pixels()[0], mapped.pixel(0, 0).unwrap());
+        assert_eq!(image.pixels()[512 * 300 + 17], mapped.pixel(17, 300).unwrap());
+        assert!(mapped.pixel(512, 0).is_err());
+
+        let deep = new_with_file_mmap(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/deep.ppm"))).unwrap();
+        let image = new_with_file_bin(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/deep.ppm"))).unwrap().load().unwrap();
+        assert_eq!(image.pixels()[7], deep.pixel(3, 1).unwrap());
+    }
+
+    #[test]
+    fn test_in_place() {
+        let file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/deep.mmap.test.ppm"));
+        std::fs::copy(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/deep.ppm")), file_path).unwrap();
+
+        let original = new_with_file_bin(file_path).unwrap().load().unwrap();
+
+        let mut mapped = new_with_file_mmap_mut(file_path).unwrap();
+        mapped.transform(ImageProcess::Invert).unwrap();
+        assert!(mapped.set_pixel(0, 0, Pixel { red: 1, green: 2, blue: 3 }));
+        mapped.flush().unwrap();
+        drop(mapped);
+
+        let inverted = new_with_file_bin(file_path).unwrap().load().unwrap();
+        assert_eq!(Pixel { red: 1, green: 2, blue: 3 }, inverted.pixels()[0]);
+        for (in_pixel, out_pixel) in original.pixels()[1..].iter().zip(&inverted.pixels()[1..]) {
+            assert_eq!(65535 - in_pixel.red, out_pixel.red);
+            assert_eq!(65535 - in_pixel.green, out_pixel.green);
+            assert_eq!(65535 - in_pixel.blue, out_pixel.blue);
+        }
+    }
+
+    #[test]
+    fn test_panicking_operation() {
+        let file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/deep.mmap.panic.test.ppm"));
+        std::fs::copy(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/deep.ppm")), file_path).unwrap();
+
+        let panicking = |_pixel: &mut BinaryPixel, _max: u32| panic!("unexpected pixel");
+
+        let mut mapped = new_with_file_mmap_mut(file_path).unwrap();
+        match mapped.transform_with(&Processor::new(2), panicking) {
+            Err(PpmError::ProcessingFailed) => (),
+            _ => panic!("expected a failed processing"),
+        }
+    }
+
+    #[test]
+    fn test_truncated() {
+        match new_with_file_mmap(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/truncated.ppm"))) {
+            Err(PpmError::TruncatedPixelData { expected, got }) => assert_eq!((12, 5), (expected, got)),
+            _ => panic!("expected a truncated pixel data error"),
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use std::io::prelude::*;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::error::PpmError;
+use crate::p6::{fill_buffer, stream_chunks, BinaryPixel, Layout};
+use crate::processor::Processor;
+use crate::progress::Monitor;
+
+/*
+    Our NeighborhoodOp trait, a transformation where each output pixel depends on the pixels around it (blur, sharpen, edge detection...).
+    Images are streamed as bands of complete rows, each one comes with halo() extra rows above and below it.
+
+    apply() computes the new value of the pixel at (x, y), reading its neighbours from the band.
+    Returned values must stay between 0 and the image's max value.
+*/
+pub trait NeighborhoodOp: Send + Sync {
+
+    // How many rows above and below a pixel the operation needs.
+    fn halo(&self) -> usize;
+
+    fn apply(&self, band: &Band, x: usize, y: usize, rgb_max_value: u32) -> BinaryPixel;
+}
+
+/*
+    Our Band structure, a few complete rows of an image, decoded.
+    Coordinates are the image's ones, not relative to the band.
+*/
+pub struct Band {
+    pixels: Vec<BinaryPixel>,
+    width: usize,
+    first_row: usize,
+    rows: Range<usize>,
+}
+
+impl Band {
+
+    // Decode rows of raw pixels, starting at row first_row of the image. rows are the ones being transformed.
+    pub(crate) fn new(bytes: &[u8], width: usize, first_row: usize, rows: Range<usize>, layout: Layout) -> Band {
+        Band {
+            pixels: bytes.chunks_exact(layout.pixel_bytes()).map(|bytes| BinaryPixel::read(bytes, layout)).collect(),
+            width,
+            first_row,
+            rows,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    // The rows being transformed, halo rows excluded.
+    pub fn rows(&self) -> Range<usize> {
+        self.rows.clone()
+    }
+
+    /*
+        pixel(x, y)
+
+        Get the pixel at (x, y), coordinates outside of the image are moved to its nearest edge.
+        Rows further than the halo from the band are moved to its nearest loaded row as well.
+    */
+    pub fn pixel(&self, x: isize, y: isize) -> BinaryPixel {
+        let loaded_rows = self.pixels.len() / self.width;
+
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(self.first_row as isize, (self.first_row + loaded_rows) as isize - 1) as usize;
+
+        self.pixels[(y - self.first_row) * self.width + x]
+    }
+}
+
+/*
+    Our Convolution structure, a square kernel of weights applied around each pixel.
+    The weighted sum of the neighbours is divided by the divisor, rounded and clamped to the max value.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Convolution {
+    size: usize,
+    weights: Vec<i32>,
+    divisor: i32,
+}
+
+impl Convolution {
+
+    /*
+        new(size, weights, divisor)
+
+        Create a size x size kernel, weights are given row by row.
+
+        Will return Result with Err if size is even, weights don't fill the kernel or divisor is 0.
+    */
+    pub fn new(size: usize, weights: Vec<i32>, divisor: i32) -> Result<Convolution, PpmError> {
+        if size.is_multiple_of(2) {
+            return Err(PpmError::InvalidKernel(format!("size must be odd, got {}", size)));
+        }
+        if weights.len() != size * size {
+            return Err(PpmError::InvalidKernel(format!("{} weights needed, got {}", size * size, weights.len())));
+        }
+        if divisor == 0 {
+            return Err(PpmError::InvalidKernel("divisor can't be 0".to_string()));
+        }
+
+        Ok(Convolution { size, weights, divisor })
+    }
+
+    // Average of the (2 * radius + 1) x (2 * radius + 1) pixels around each pixel.
+    pub fn box_blur(radius: usize) -> Convolution {
+        let size = radius * 2 + 1;
+        Convolution {
+            size,
+            weights: vec![1; size * size],
+            divisor: (size * size) as i32,
+        }
+    }
+
+    pub fn sharpen() -> Convolution {
+        Convolution {
+            size: 3,
+            weights: vec![0, -1, 0, -1, 5, -1, 0, -1, 0],
+            divisor: 1,
+        }
+    }
+
+    // Laplacian edge detection, flat areas become black.
+    pub fn edge_detect() -> Convolution {
+        Convolution {
+            size: 3,
+            weights: vec![-1, -1, -1, -1, 8, -1, -1, -1, -1],
+            divisor: 1,
+        }
+    }
+}
+
+impl NeighborhoodOp for Convolution {
+
+    fn halo(&self) -> usize {
+        self.size / 2
+    }
+
+    fn apply(&self, band: &Band, x: usize, y: usize, rgb_max_value: u32) -> BinaryPixel {
+        let radius = (self.size / 2) as isize;
+        let mut sums = [0i64; 3];
+
+        for (i, weight) in self.weights.iter().enumerate() {
+            let dx = (i % self.size) as isize - radius;
+            let dy = (i / self.size) as isize - radius;
+            let pixel = band.pixel(x as isize + dx, y as isize + dy);
+
+            sums[0] += *weight as i64 * pixel.r as i64;
+            sums[1] += *weight as i64 * pixel.g as i64;
+            sums[2] += *weight as i64 * pixel.b as i64;
+        }
+
+        let sample = |sum: i64| (sum as f64 / self.divisor as f64).round().clamp(0.0, rgb_max_value as f64) as u32;
+
+        BinaryPixel {
+            r: sample(sums[0]),
+            g: sample(sums[1]),
+            b: sample(sums[2]),
+        }
+    }
+}
+
+/*
+    process_bands(processor, monitor, reader, writer, width, height, layout, rgb_max_value, op)
+
+    Stream the pixels section as bands of complete rows, each one handed to a worker with op.halo() rows above and below it.
+    Rows are read once, halo rows are kept from one band to the next. Progress is reported to the monitor as bands are written.
+
+    Return a Result with nothing if everything went smooth.
+*/
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn process_bands<R: Read, W: Write + Send>(
+    processor: &Processor,
+    monitor: &Monitor,
+    reader: &mut R,
+    writer: &mut W,
+    width: usize,
+    height: usize,
+    layout: Layout,
+    rgb_max_value: u32,
+    op: Arc<dyn NeighborhoodOp>,
+) -> Result<(), PpmError> {
+
+    let row_bytes = width * layout.pixel_bytes();
+    let halo = op.halo();
+
+    // Raw rows read so far and still needed, from window_start to window_end.
+    let mut window: Vec<u8> = vec![];
+    let mut window_start = 0;
+    let mut window_end = 0;
+
+    stream_chunks(processor, monitor, writer, (height, row_bytes), |rows, chunk| {
+
+        // Forget the rows no band needs anymore.
+        let needed_start = rows.start.saturating_sub(halo);
+        window.drain(..(needed_start - window_start) * row_bytes);
+        window_start = needed_start;
+
+        // Read the rows up to the bottom halo, hitting the end of file early means the pixels section is truncated.
+        let needed_end = (rows.end + halo).min(height);
+        let filled_length = window.len();
+        window.resize(filled_length + (needed_end - window_end) * row_bytes, 0);
+
+        let filled = fill_buffer(reader, &mut window[filled_length..])?;
+        if filled_length + filled < window.len() {
+            return Err(PpmError::TruncatedPixelData {
+                expected: width * height * layout.depth,
+                got: (window_end * row_bytes + filled) / layout.sample_bytes,
+            });
+        }
+        window_end = needed_end;
+
+        // The band comes with its halo rows.
+        chunk.clear();
+        chunk.extend_from_slice(&window);
+
+        let op = Arc::clone(&op);
+        let first_row = window_start;
+
+        Ok(move |chunk: Vec<u8>| {
+            let band = Band::new(&chunk, width, first_row, rows.clone(), layout);
+
+            // Start from the raw rows, so samples we don't transform (like alpha) are kept.
+            let mut output = chunk[(rows.start - first_row) * row_bytes..(rows.end - first_row) * row_bytes].to_vec();
+
+            for (bytes, index) in output.chunks_exact_mut(layout.pixel_bytes()).zip(0..) {
+                let (x, y) = (index % width, rows.start + index / width);
+                op.apply(&band, x, y, rgb_max_value).write(bytes, layout);
+            }
+
+            output
+        })
+    })
+}
+
+// Module for testing
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::p6::{new_with_file_bin, new_with_reader_bin};
+    use crate::progress::Progress;
+    use std::io::Cursor;
+    use std::path::Path;
+
+    fn get_test_file_path() -> &'static Path {
+        Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/alaska.ppm"))
+    }
+
+    #[test]
+    fn test_bands_match_whole_image() {
+        let mut img = new_with_file_bin(get_test_file_path()).unwrap();
+        let layout = Layout::rgb(img.rgb_max_value);
+
+        // The whole image in a single band, as a reference.
+        let mut bytes = vec![];
+        img.load().unwrap().write_to(&mut bytes, crate::image::Format::Binary).unwrap();
+        let bytes = &bytes[bytes.len() - img.width * img.height * 3..];
+
+        for op in [Convolution::box_blur(2), Convolution::sharpen(), Convolution::edge_detect()] {
+            let band = Band::new(bytes, img.width, 0, 0..img.height, layout);
+
+            let mut expected = vec![0; bytes.len()];
+            for (pixel, index) in expected.chunks_exact_mut(3).zip(0..) {
+                op.apply(&band, index % img.width, index / img.width, 255).write(pixel, layout);
+            }
+
+            // Many workers means many small bands, each one needing its neighbours' rows.
+            let mut output: Vec<u8> = vec![];
+            img.filter_with(&Processor::new(64), &mut output, op).unwrap();
+
+            assert_eq!(expected, output[output.len() - expected.len()..].to_vec());
+        }
+    }
+
+    #[test]
+    fn test_box_blur_edges() {
+        let input = b"P6\n3 1\n255\n\x00\x00\x00\x00\x00\x00\x90\x90\x90".to_vec();
+
+        let mut output: Vec<u8> = vec![];
+        new_with_reader_bin(Cursor::new(input)).unwrap().filter_and_write(&mut output, Convolution::box_blur(1)).unwrap();
+
+        // Edges are extended, on each row the last pixel sees itself twice and its left neighbour once.
+        assert_eq!(b"\x00\x00\x00\x30\x30\x30\x60\x60\x60".to_vec(), output[11..].to_vec());
+    }
+
+    #[test]
+    fn test_filter_monitored() {
+        let reports = Arc::new(std::sync::Mutex::new(vec![]));
+        let monitor = {
+            let reports = Arc::clone(&reports);
+            Monitor::new().on_progress(move |progress| reports.lock().unwrap().push(progress))
+        };
+
+        let mut img = new_with_file_bin(get_test_file_path()).unwrap();
+        let mut output: Vec<u8> = vec![];
+        img.filter_monitored(&Processor::new(64), &mut output, Convolution::sharpen(), &monitor).unwrap();
+
+        let total = (img.width * img.height * 3) as u64;
+        let reports = reports.lock().unwrap();
+        assert!(reports.len() > 1);
+        assert_eq!(Progress { done: total, total }, *reports.last().unwrap());
+
+        let token = crate::progress::CancellationToken::new();
+        token.cancel();
+        match img.filter_monitored(&Processor::new(2), &mut output, Convolution::sharpen(), &Monitor::new().with_cancellation(token)) {
+            Err(PpmError::Cancelled) => (),
+            _ => panic!("expected a cancelled filter"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_kernel() {
+        assert!(Convolution::new(2, vec![1; 4], 1).is_err());
+        assert!(Convolution::new(3, vec![1; 8], 1).is_err());
+        assert!(Convolution::new(3, vec![1; 9], 0).is_err());
+        assert_eq!(Convolution::box_blur(1), Convolution::new(3, vec![1; 9], 9).unwrap());
+    }
+}
@@ -4,160 +4,253 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::io::{BufRead, BufReader};
 use std::path::Path;
-
-// use libc::double;
-#[derive(Copy, Clone)]
-pub struct Pixel {
-    red: u8,
-    blue: u8,
-    green: u8,
+use std::sync::Arc;
+
+use crate::error::PpmError;
+use crate::header::{read_header_tokens, write_header, Comment, Tokenizer, ASCII_LINE_LENGTH};
+pub use crate::image::{Format, Image, Pixel};
+use crate::image::Kind;
+use crate::p6::{output_file, stream_chunks, transform_chunk, Layout, Pipeline};
+use crate::pgm::read_ascii_samples;
+use crate::processor::Processor;
+use crate::progress::Monitor;
+
+/*
+    Our AsciiImage structure, the P3 counterpart of p6::BinaryImage.
+    Only the headers are stored, pixels are parsed, processed and written chunk by chunk, so huge ASCII images never sit in memory.
+    The reader only has to be buffered, not seekable: stdin or a socket work too. As a consequence an image can only be processed once.
+*/
+pub struct AsciiImage<R: BufRead = BufReader<File>> {
+    pub reader: R,
+    pub height: usize,
+    pub width: usize,
+    pub rgb_max_value: usize,
+    // Comments of the headers, written back before the processed pixels.
+    pub comments: Vec<Comment>,
+    // Line and column the pixels section starts at, for error messages.
+    position: (usize, usize),
 }
 
-pub struct Image {
-    pixels: Vec<Pixel>,
-    heigth: usize,
-    width: usize,
-    rgb_type: String,
-    max_val: u8,
-}
+impl<R: BufRead> AsciiImage<R> {
 
-impl Pixel {
+    /*
+        process_and_output(filename, process)
 
-    pub fn invert(&mut self) {
-        self.red = 255 - self.red;
-        self.blue = 255 - self.blue;
-        self.green = 255 - self.green;
+        Process (transform) image and output it to a file, as a P3 image.
+        Just like BinaryImage::process_and_output(), process is one of ImageProcess enum's values, a Pipeline or your own operation.
+
+        Return a Result with nothing if everything went smooth.
+    */
+    pub fn process_and_output<P: Into<Pipeline>>(self, filename: &Path, process: P) -> Result<(), PpmError> {
+        self.process_and_output_monitored(filename, process, &Monitor::new())
     }
 
-    pub fn eq(self, other: Pixel) -> bool {
-        return self.red == other.red && self.blue == other.blue && self.green == other.green;
+    // Same as process_and_output(), but progress is reported to the monitor and the process can be cancelled through it. No partial output file is left behind.
+    pub fn process_and_output_monitored<P: Into<Pipeline>>(self, filename: &Path, process: P, monitor: &Monitor) -> Result<(), PpmError> {
+        output_file(filename, |writer| self.process_monitored(&Processor::default(), writer, process, monitor))
     }
 
-    pub fn greyscale(&mut self) {
-        let gris: u32 = ((self.red as u32 + self.blue as u32 + self.green as u32) / 3) as u32;
-        self.red = (gris) as u8;
-        self.blue = (gris) as u8;
-        self.green = (gris) as u8;
+    // Same as process_and_output(), but the processed image goes to any writer, which is not flushed.
+    pub fn process_and_write<W: Write + Send, P: Into<Pipeline>>(self, writer: &mut W, process: P) -> Result<(), PpmError> {
+        self.process_with(&Processor::default(), writer, process)
     }
-}
 
-impl PartialEq for Pixel {
-    fn eq(&self, other: &Self) -> bool {
-        self.red == other.red && self.blue == other.blue && self.green == other.green
+    /*
+        process_with(processor, writer, process)
+
+        Same as process_and_write(), on an existing Processor's workers.
+        Samples are parsed on the caller's thread, while workers transform the previous chunks and format them back to text.
+
+        Will return Result with Err if a sample is invalid (with its line and column) or the pixels section is truncated.
+    */
+    pub fn process_with<W: Write + Send, P: Into<Pipeline>>(self, processor: &Processor, writer: &mut W, process: P) -> Result<(), PpmError> {
+        self.process_monitored(processor, writer, process, &Monitor::new())
     }
-}
 
-//  Function that read in text mode a ppm image
-pub fn new_with_file(filename: &Path) -> Image {
-    
-    let file = File::open(filename).unwrap();
-    let reader = BufReader::new(file);
-    let mut final_pixels: Vec<Pixel> = vec![];
-    let mut final_height: usize = 0;
-    let mut final_width: usize = 0;
-    let mut final_max_value: u8 = 0;
-    let mut type_rgb = String::from("");
-    
-    for (_index, line) in reader.lines().enumerate() {
-        
-        let line = line.unwrap();
-
-        let parsed_line: Vec<&str> = line.trim().split(" ").collect();
-        if parsed_line[0].chars().next().unwrap() != '#' {
-            if parsed_line.len() == 2 {
-                final_height = parsed_line[0].parse::<usize>().unwrap();
-                final_width = parsed_line[1].parse::<usize>().unwrap();
-            } else if parsed_line.len() >= 3 {
-                
-                let mut index = 0;
-                let mut redf: u8 = 0;
-                let mut bluef: u8 = 0;
-                let mut greenf: u8 = 0;
-
-                for i in 0..parsed_line.len() {
-                    if parsed_line[i].is_empty() == false
-                        && parsed_line[i].find(|c: char| (c < '0') || (c > '9')) == None
-                    {
-                        //println!("{} {}\n", parsedLine[i], i);
-                        match index {
-                            // Match a single value
-                            0 => redf = parsed_line[i].parse::<u8>().unwrap(),
-                            1 => bluef = parsed_line[i].parse::<u8>().unwrap(),
-                            2 => greenf = parsed_line[i].parse::<u8>().unwrap(),
-                            _ => redf = redf + 0,
-                        }
-
-                        index = index + 1;
-
-                        if index == 3 {
-                            let final_pixel = Pixel {
-                                red: redf,
-                                blue: bluef,
-                                green: greenf,
-                            };
-
-                            final_pixels.push(final_pixel);
-                            index = 0;
-                        }
+    /*
+        process_monitored(processor, writer, process, monitor)
+
+        Same as process_with(), but progress is reported to the monitor and the process can be cancelled through it.
+        Progress counts the parsed pixels as binary bytes (width * height * 3 samples, 2 bytes each above 255), not the written text.
+
+        Return a Result with nothing if everything went smooth, or PpmError::Cancelled.
+    */
+    pub fn process_monitored<W: Write + Send, P: Into<Pipeline>>(
+        mut self,
+        processor: &Processor,
+        writer: &mut W,
+        process: P,
+        monitor: &Monitor,
+    ) -> Result<(), PpmError> {
+        write_header(writer, Kind::Pixmap, Format::Ascii, (self.width, self.height), self.rgb_max_value, &self.comments)?;
+
+        let (width, height) = (self.width, self.height);
+        let layout = Layout::rgb(self.rgb_max_value);
+        let rgb_max_value = self.rgb_max_value as u32;
+
+        // Samples are parsed into binary rows, just like the ones streamed from P6 files.
+        let row_bytes = width * layout.pixel_bytes();
+        let pipeline = Arc::new(process.into());
+
+        let mut tokens = Tokenizer::at(&mut self.reader, self.position);
+
+        stream_chunks(processor, monitor, writer, (height, row_bytes), |rows, chunk| {
+
+            for (sample, index) in chunk.chunks_exact_mut(layout.sample_bytes).zip(0..) {
+                let token = match tokens.next_token()? {
+                    Some(token) => token,
+                    None => {
+                        return Err(PpmError::TruncatedPixelData {
+                            expected: width * height * 3,
+                            got: rows.start * width * 3 + index,
+                        })
                     }
-                }
-            } else if parsed_line.len() == 1 {
-                if parsed_line[0].find(|c: char| (c < '0') || (c > '9')) == None {
-                    final_max_value = parsed_line[0].parse::<u8>().unwrap();
-                } else {
-                    type_rgb = parsed_line[0].to_string();
+                };
+
+                match token.number() {
+                    Some(value) if value <= rgb_max_value as usize => match layout.sample_bytes {
+                        1 => sample[0] = value as u8,
+                        _ => sample.copy_from_slice(&(value as u16).to_be_bytes()),
+                    },
+                    _ => return Err(PpmError::InvalidSample { line: token.line, column: token.column }),
                 }
             }
-        }
-        // still lines wich contains # or the of RGB color
+
+            let pipeline = Arc::clone(&pipeline);
+            Ok(move |mut chunk: Vec<u8>| {
+                transform_chunk(&mut chunk, width, rows.start, &(0..height), layout, rgb_max_value, &pipeline);
+
+                let mut text = Vec::with_capacity(chunk.len() * 4);
+                for row in chunk.chunks_exact(row_bytes) {
+                    format_row(&mut text, row.chunks_exact(layout.sample_bytes).map(|sample| match sample {
+                        [byte] => *byte as u16,
+                        _ => u16::from_be_bytes([sample[0], sample[1]]),
+                    }));
+                }
+
+                text
+            })
+        })
     }
-    return Image {
-        pixels: final_pixels,
-        heigth: final_height,
-        width: final_width,
-        rgb_type: type_rgb,
-        max_val: final_max_value,
-    };
 }
 
-impl Image {
-    //  saves Image into a file
-    pub fn save(&self, filename: &Path) {
-        let mut file = File::create(filename).unwrap();
-        file.write_all(self.rgb_type.as_bytes()).unwrap();
-        file.write_all(b"\n").unwrap();
-        let second_line = String::from(self.heigth.to_string() + " " + &self.width.to_string());
-        file.write_all(second_line.as_bytes()).unwrap();
-        file.write_all(b"\n").unwrap();
-        file.write_all(self.max_val.to_string().as_bytes()).unwrap();
-        file.write_all(b"\n").unwrap();
-        for i in 0..self.pixels.len() {
-            let pixel_line = String::from(
-                self.pixels[i].red.to_string()
-                    + " "
-                    + &self.pixels[i].blue.to_string()
-                    + " "
-                    + &self.pixels[i].green.to_string(),
-            );
-            file.write_all(pixel_line.as_bytes()).unwrap();
-            file.write_all(b"\n").unwrap();
-        }
-        //close the file
-        drop(file);
+/*
+    new_with_file_ascii(filename)
+
+    Read the headers of a P3 file, this struct could then be used with process_and_output(out_filename, process).
+
+    Will return Result with Err if the file is not a P3 image or its headers are invalid.
+*/
+pub fn new_with_file_ascii(filename: &Path) -> Result<AsciiImage, PpmError> {
+    new_with_reader_ascii(BufReader::new(File::open(filename)?))
+}
+
+// Same as new_with_file_ascii(), for any buffered reader.
+pub fn new_with_reader_ascii<R: BufRead>(mut reader: R) -> Result<AsciiImage<R>, PpmError> {
+
+    let mut tokens = Tokenizer::new(&mut reader);
+    let header = read_header_tokens(&mut tokens)?;
+
+    if (header.kind, header.format) != (Kind::Pixmap, Format::Ascii) {
+        return Err(PpmError::BadMagic(header.kind.magic_number(header.format).to_string()));
     }
 
-    //    function that inverts image colors
-    pub fn invert(&mut self) {
-        for i in 0..self.pixels.len() {
-            self.pixels[i].invert();
+    let position = tokens.position();
+
+    Ok(AsciiImage {
+        reader,
+        height: header.height,
+        width: header.width,
+        rgb_max_value: header.max_value,
+        comments: header.comments,
+        position,
+    })
+}
+
+/*
+    Append a row of samples as text, on a new line.
+    Samples are separated by a space, and lines are wrapped before they get longer than the spec allows.
+*/
+pub(crate) fn format_row<I: Iterator<Item = u16>>(text: &mut Vec<u8>, samples: I) {
+    let mut line_length = 0;
+
+    for sample in samples {
+
+        // Digits are written from the last one, a u16 has 5 at most.
+        let mut digits = [0u8; 5];
+        let mut start = digits.len();
+        let mut value = sample;
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (value % 10) as u8;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
         }
-    }
-    //    function that makes image B&W based on a filter color
-    pub fn greyscale(&mut self) {
-        for i in 0..self.pixels.len() {
-            self.pixels[i].greyscale();
+
+        let length = digits.len() - start;
+        if line_length > 0 {
+            if line_length + 1 + length > ASCII_LINE_LENGTH {
+                text.push(b'\n');
+                line_length = 0;
+            } else {
+                text.push(b' ');
+                line_length += 1;
+            }
         }
+
+        text.extend_from_slice(&digits[start..]);
+        line_length += length;
+    }
+
+    text.push(b'\n');
+}
+
+//  Function that read in text mode a ppm image
+pub fn new_with_file(filename: &Path) -> Result<Image, PpmError> {
+    
+    let file = File::open(filename)?;
+    new_with_reader(BufReader::new(file))
+}
+
+//  Same as new_with_file(), for any buffered reader: stdin, a socket or bytes already in memory.
+//  Headers and samples may be laid out on lines in any way the spec allows, with comments after any value.
+pub fn new_with_reader<R: BufRead>(mut reader: R) -> Result<Image, PpmError> {
+
+    let mut tokens = Tokenizer::new(&mut reader);
+    let header = read_header_tokens(&mut tokens)?;
+
+    if (header.kind, header.format) != (Kind::Pixmap, Format::Ascii) {
+        return Err(PpmError::BadMagic(header.kind.magic_number(header.format).to_string()));
     }
+
+    let samples = read_ascii_samples(&mut tokens, (header.width * header.height) * 3, header.max_value)?;
+
+    let pixels = samples
+        .chunks_exact(3)
+        .map(|rgb| Pixel {
+            red: rgb[0],
+            green: rgb[1],
+            blue: rgb[2],
+        })
+        .collect();
+
+    Ok(Image::with_pixels(&header, pixels))
+}
+
+//  Function that writes an image in text mode (P3), one row per line, wrapped at 70 characters.
+pub fn write_image<W: Write>(image: &Image, writer: &mut W) -> Result<(), PpmError> {
+    write_header(writer, Kind::Pixmap, Format::Ascii, (image.width, image.height), image.max_value as usize, &image.comments)?;
+
+    let mut text = vec![];
+    for row in image.pixels.chunks(image.width.max(1)) {
+        text.clear();
+        format_row(&mut text, row.iter().flat_map(|pixel| [pixel.red, pixel.green, pixel.blue]));
+        writer.write_all(&text)?;
+    }
+
+    Ok(())
 }
 
 //Tests and Benchmark
@@ -171,15 +264,15 @@ mod bench {
     }
 
     fn get_test_output_file_path() -> &'static str {
-        concat!(env!("CARGO_MANIFEST_DIR"), "/src/p3/test/test.output.ppm")
+        concat!(env!("CARGO_MANIFEST_DIR"), "/src/p3/test/test.output.bench.ppm")
     }
 
     #[test]
     fn test_invert() {
         let test_file = get_test_file_path();
 
-        let mut image = new_with_file(Path::new(&test_file));
-        let image_aux = new_with_file(Path::new(&test_file));
+        let mut image = new_with_file(Path::new(&test_file)).unwrap();
+        let image_aux = new_with_file(Path::new(&test_file)).unwrap();
 
         image.invert();
 
@@ -195,8 +288,8 @@ mod bench {
     fn test_greyscale() {
         let test_file = Path::new(get_test_file_path());
 
-        let mut image = new_with_file(&test_file);
-        let image_aux = new_with_file(&test_file);
+        let mut image = new_with_file(test_file).unwrap();
+        let image_aux = new_with_file(test_file).unwrap();
 
         image.greyscale();
 
@@ -226,11 +319,144 @@ mod bench {
         }
     }
 
+    #[test]
+    fn test_invalid_sample() {
+        let test_file = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p3/test/invalid.ppm"));
+
+        match new_with_file(test_file) {
+            Err(PpmError::InvalidSample { line, column }) => assert_eq!((5, 3), (line, column)),
+            _ => panic!("expected an invalid sample error"),
+        }
+    }
+
+    #[test]
+    fn test_sample_above_maxval() {
+        let test_file = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p3/test/above_maxval.ppm"));
+
+        match new_with_file(test_file) {
+            Err(PpmError::InvalidSample { line, column }) => assert_eq!((4, 4), (line, column)),
+            _ => panic!("expected an invalid sample error"),
+        }
+    }
+
+    #[test]
+    fn test_free_layout() {
+        // Header on one line, samples wrapped anywhere, comments after values and inside the header.
+        let input = "P3 3 # width\n1 255\n255 0\n0 # red\n0 255 0 0 0\n255\n";
+
+        let image = new_with_reader(input.as_bytes()).unwrap();
+        assert_eq!((3, 1), (image.width(), image.height()));
+        assert_eq!(
+            vec![
+                Pixel { red: 255, green: 0, blue: 0 },
+                Pixel { red: 0, green: 255, blue: 0 },
+                Pixel { red: 0, green: 0, blue: 255 },
+            ],
+            image.pixels().to_vec()
+        );
+    }
+
+    #[test]
+    fn test_header_error_position() {
+        match new_with_reader("P3\n# comment\n2 x1\n255\n".as_bytes()) {
+            Err(PpmError::BadDimension(value)) => assert_eq!("x1 at line 3, column 3", value),
+            _ => panic!("expected a bad dimension error"),
+        }
+
+        match new_with_reader("P3 1 1\n255 1 2".as_bytes()) {
+            Err(PpmError::TruncatedPixelData { expected, got }) => assert_eq!((3, 2), (expected, got)),
+            _ => panic!("expected a truncated pixel data error"),
+        }
+
+        match new_with_reader("P3 1 1 255 1 -2 3".as_bytes()) {
+            Err(PpmError::InvalidSample { line, column }) => assert_eq!((1, 14), (line, column)),
+            _ => panic!("expected an invalid sample error"),
+        }
+    }
+
+    #[test]
+    fn test_streaming_matches_image() {
+        for name in ["test.ppm", "deep.ppm"] {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/p3/test").join(name);
+
+            let mut image = new_with_file(&path).unwrap();
+            image.invert();
+            let mut expected: Vec<u8> = vec![];
+            write_image(&image, &mut expected).unwrap();
+
+            let mut output: Vec<u8> = vec![];
+            new_with_file_ascii(&path).unwrap().process_with(&Processor::new(4), &mut output, crate::p6::ImageProcess::Invert).unwrap();
+
+            assert_eq!(String::from_utf8(expected).unwrap(), String::from_utf8(output).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_streaming_progress() {
+        let reports = Arc::new(std::sync::Mutex::new(vec![]));
+        let monitor = {
+            let reports = Arc::clone(&reports);
+            crate::progress::Monitor::new().on_progress(move |progress| reports.lock().unwrap().push(progress))
+        };
+
+        let image = new_with_file_ascii(Path::new(get_test_file_path())).unwrap();
+        let total = (image.width * image.height * 3) as u64;
+
+        let mut output: Vec<u8> = vec![];
+        image.process_monitored(&Processor::new(2), &mut output, crate::p6::ImageProcess::Invert, &monitor).unwrap();
+
+        // Counted in parsed samples, the text written is longer.
+        assert_eq!(crate::progress::Progress { done: total, total }, *reports.lock().unwrap().last().unwrap());
+    }
+
+    #[test]
+    fn test_streaming_line_length() {
+        // Wide rows of 5 digits samples, streamed in many small chunks.
+        let (width, height) = (50, 30);
+        let mut input = format!("P3\n{} {}\n65535\n", width, height);
+        for i in 0..width * height * 3 {
+            input.push_str(&format!("{}\n", (i * 997) % 65536));
+        }
+
+        let mut output: Vec<u8> = vec![];
+        new_with_reader_ascii(input.as_bytes()).unwrap().process_with(&Processor::new(64), &mut output, crate::p6::ImageProcess::Greyscale).unwrap();
+
+        let text = String::from_utf8(output).unwrap();
+        assert!(text.lines().all(|line| line.len() <= ASCII_LINE_LENGTH));
+
+        let mut expected = new_with_reader(input.as_bytes()).unwrap();
+        expected.greyscale();
+        assert_eq!(expected.pixels(), new_with_reader(text.as_bytes()).unwrap().pixels());
+    }
+
+    #[test]
+    fn test_streaming_errors() {
+        let test_file = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p3/test/invalid.ppm"));
+
+        match new_with_file_ascii(test_file).unwrap().process_and_write(&mut vec![], crate::p6::ImageProcess::Invert) {
+            Err(PpmError::InvalidSample { line, column }) => assert_eq!((5, 3), (line, column)),
+            _ => panic!("expected an invalid sample error"),
+        }
+
+        match new_with_reader_ascii("P3 2 1 255 1 2 3 4".as_bytes()).unwrap().process_and_write(&mut vec![], crate::p6::ImageProcess::Invert) {
+            Err(PpmError::TruncatedPixelData { expected, got }) => assert_eq!((6, 4), (expected, got)),
+            _ => panic!("expected a truncated pixel data error"),
+        }
+    }
+
+    #[bench]
+    fn bench_invert_image_streaming(b: &mut Bencher) {
+        let test_file = Path::new(get_test_file_path());
+        let test_file_output = Path::new(get_test_output_file_path());
+
+        b.iter(|| new_with_file_ascii(test_file).unwrap().process_and_output(test_file_output, crate::p6::ImageProcess::Invert).unwrap());
+    }
+
     #[bench]
     fn bench_create_file(b: &mut Bencher) {
         let test_file = Path::new(get_test_file_path());
 
-        b.iter(|| new_with_file(&test_file));
+        b.iter(|| new_with_file(test_file));
     }
 
     #[bench]
@@ -238,8 +464,8 @@ mod bench {
         let test_file = Path::new(get_test_file_path());
         let test_file_output = Path::new(get_test_output_file_path());
 
-        let image = new_with_file(&test_file);
-        b.iter(|| image.save(&test_file_output));
+        let image = new_with_file(test_file).unwrap();
+        b.iter(|| image.save(test_file_output));
     }
 
     #[bench]
@@ -247,9 +473,9 @@ mod bench {
         let test_file = Path::new(get_test_file_path());
         let test_file_output = Path::new(get_test_output_file_path());
 
-        let mut image = new_with_file(&test_file);
+        let mut image = new_with_file(test_file).unwrap();
         image.greyscale();
-        b.iter(|| image.save(&test_file_output));
+        b.iter(|| image.save(test_file_output));
     }
 
     #[bench]
@@ -257,8 +483,8 @@ mod bench {
         let test_file = Path::new(get_test_file_path());
         let test_file_output = Path::new(get_test_output_file_path());
 
-        let mut image = new_with_file(&test_file);
+        let mut image = new_with_file(test_file).unwrap();
         image.invert();
-        b.iter(|| image.save(&test_file_output));
+        b.iter(|| image.save(test_file_output));
     }
 }
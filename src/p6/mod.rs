@@ -1,56 +1,347 @@
+use std::any::Any;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::{BufReader, BufWriter, Error, ErrorKind, SeekFrom};
+use std::io::{BufReader, BufWriter, ErrorKind, SeekFrom};
+use std::panic::{self, AssertUnwindSafe};
+use std::ops::Range;
 use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+
+use crate::error::PpmError;
+use crate::header::{position_after, read_header_tokens, write_header, Comment, Tokenizer};
+use crate::image::{Format, Image, Kind, Pixel};
+use crate::kernels::Kernel;
+use crate::neighborhood::{process_bands, NeighborhoodOp};
+use crate::processor::Processor;
+use crate::progress::{Monitor, Progress};
 
 extern crate test;
-extern crate num_cpus;
 
-// Here we use u32 because we still need to implement support for > 255 rgb_max_value files.
+// Here we use u32 so that both 8 bits and 16 bits samples fit, and sums don't overflow.
+// Grey pixels are seen as r = g = b, only the red value is written back.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
 pub struct BinaryPixel {
-    r: u32,
-    g: u32,
-    b: u32,
+    pub r: u32,
+    pub g: u32,
+    pub b: u32,
+}
+
+/*
+    How samples are laid out inside a binary pixel.
+    Color samples come first: 3 for RGB, or a single one for grey which is seen as r = g = b.
+    Any other sample after them (like PAM's alpha channel) is never touched by our processes.
+*/
+#[derive(Copy, Clone)]
+pub(crate) struct Layout {
+    pub(crate) sample_bytes: usize,
+    pub(crate) depth: usize,
+    pub(crate) color_samples: usize,
+}
+
+impl Layout {
+
+    // The layout of P6 pixels.
+    pub(crate) fn rgb(rgb_max_value: usize) -> Layout {
+        Layout {
+            sample_bytes: bytes_per_sample(rgb_max_value),
+            depth: 3,
+            color_samples: 3,
+        }
+    }
+
+    pub(crate) fn pixel_bytes(self) -> usize {
+        self.sample_bytes * self.depth
+    }
+}
+
+impl BinaryPixel {
+
+    // Decode the color samples of a pixel, each one is 1 byte (8 bits samples) or 2 big-endian bytes (16 bits samples).
+    pub(crate) fn read(bytes: &[u8], layout: Layout) -> BinaryPixel {
+        let sample = |i: usize| match layout.sample_bytes {
+            1 => bytes[i] as u32,
+            _ => u16::from_be_bytes([bytes[i * 2], bytes[(i * 2) + 1]]) as u32,
+        };
+
+        match layout.color_samples {
+            1 => BinaryPixel {
+                r: sample(0),
+                g: sample(0),
+                b: sample(0),
+            },
+            _ => BinaryPixel {
+                r: sample(0),
+                g: sample(1),
+                b: sample(2),
+            },
+        }
+    }
+
+    // Encode the color samples back, a grey pixel only writes its red value.
+    pub(crate) fn write(&self, bytes: &mut [u8], layout: Layout) {
+        let mut set = |i: usize, value: u32| match layout.sample_bytes {
+            1 => bytes[i] = value as u8,
+            _ => bytes[(i * 2)..((i * 2) + 2)].copy_from_slice(&(value as u16).to_be_bytes()),
+        };
+
+        set(0, self.r);
+
+        if layout.color_samples == 3 {
+            set(1, self.g);
+            set(2, self.b);
+        }
+    }
 }
 
-// Invert a pixel's values.
-pub fn invert_binary_pixel(pixel: &mut BinaryPixel) -> () {
-    pixel.r = 255 - pixel.r;
-    pixel.b = 255 - pixel.b;
-    pixel.g = 255 - pixel.g;
+// Invert a pixel's values, relative to the image's RGB max value.
+// Out of range samples (above the max value) are clamped to 0 instead of wrapping around.
+pub fn invert_binary_pixel(pixel: &mut BinaryPixel, rgb_max_value: u32) {
+    pixel.r = rgb_max_value.saturating_sub(pixel.r);
+    pixel.b = rgb_max_value.saturating_sub(pixel.b);
+    pixel.g = rgb_max_value.saturating_sub(pixel.g);
 }
 
 // Turn a pixel into greyscale.
-pub fn greyscale_binary_pixel(pixel: &mut BinaryPixel) -> () {
+pub fn greyscale_binary_pixel(pixel: &mut BinaryPixel, _rgb_max_value: u32) {
     let grey = (pixel.r + pixel.b + pixel.g) / 3 ;
     pixel.r = grey;
     pixel.b = grey;
     pixel.g = grey;
 }
 
+// Add delta to a pixel's values (a negative one darkens it), clamped between 0 and the image's RGB max value.
+pub fn brighten_binary_pixel(pixel: &mut BinaryPixel, delta: i32, rgb_max_value: u32) {
+    let brighten = |sample: u32| (sample as i64 + delta as i64).clamp(0, rgb_max_value as i64) as u32;
+    pixel.r = brighten(pixel.r);
+    pixel.g = brighten(pixel.g);
+    pixel.b = brighten(pixel.b);
+}
+
 /* 
     Our BinaryImage structure.
     The only data which is stored inside it are the headers.
     Obviously we don't store any pixels, just the starting offset.
     This is quite different from the standard Image struct, since it is used for bufferized processing.
+    Most methods need a seekable reader, a file by default, or an in-memory Cursor.
+    Images read from stdin, a socket or an HTTP body (see new_with_stream_bin()) are processed once, with process_stream().
 */
-pub struct BinaryImage {
-    pub reader: BufReader<File>,
+pub struct BinaryImage<R: BufRead = BufReader<File>> {
+    pub reader: R,
     pub magic_number: String,
     pub pixels_offset: usize,
     pub height: usize,
     pub width: usize,
     pub rgb_max_value: usize,
+    // Comments of the headers, written back by the processing functions.
+    pub comments: Vec<Comment>,
+    // Line and column the pixels section starts at, for error messages.
+    position: (usize, usize),
 }
 
 // Our implemented image transformation processes.
-// These values must be used with process_and_output() second parameter.
+// These values can be used with process_and_output() second parameter, alone or chained in a Pipeline.
+#[derive(Clone, Debug, PartialEq)]
 pub enum ImageProcess {
     Invert,
     Greyscale,
+    // Add this value to every sample, negative values darken the image.
+    Brightness(i32),
 }
 
-impl BinaryImage {
+/*
+    Our PixelOp trait, a per-pixel transformation that can be streamed over huge images.
+    Implement it (or pass a closure) to plug your own processes in, they get the same multi-threaded treatment as ours.
+
+    apply() gets the image's max value, and must leave every value between 0 and that max value.
+    Streaming processes call apply_at() with the pixel's (x, y) coordinates, override it for position-dependent effects.
+    Operations are shared between worker threads, so they must be Send and Sync.
+*/
+pub trait PixelOp: Send + Sync {
+    fn apply(&self, pixel: &mut BinaryPixel, rgb_max_value: u32);
+
+    fn apply_at(&self, pixel: &mut BinaryPixel, _x: usize, _y: usize, rgb_max_value: u32) {
+        self.apply(pixel, rgb_max_value)
+    }
+}
+
+impl PixelOp for ImageProcess {
+
+    // apply the right function to a pixel.
+    fn apply(&self, pixel: &mut BinaryPixel, rgb_max_value: u32) {
+        match self {
+            ImageProcess::Invert => invert_binary_pixel(pixel, rgb_max_value),
+            ImageProcess::Greyscale => greyscale_binary_pixel(pixel, rgb_max_value),
+            ImageProcess::Brightness(delta) => brighten_binary_pixel(pixel, *delta, rgb_max_value),
+        }
+    }
+}
+
+// Any closure taking a pixel and the max value is an operation.
+impl<F: Fn(&mut BinaryPixel, u32) + Send + Sync> PixelOp for F {
+    fn apply(&self, pixel: &mut BinaryPixel, rgb_max_value: u32) {
+        self(pixel, rgb_max_value)
+    }
+}
+
+/*
+    Positioned(closure)
+
+    Turn a closure taking a pixel, its (x, y) coordinates and the max value into an operation, for vignettes, gradients or masks.
+    Without coordinates (a plain apply() call) the pixel is seen as being at (0, 0).
+*/
+pub struct Positioned<F>(pub F);
+
+impl<F: Fn(&mut BinaryPixel, usize, usize, u32) + Send + Sync> PixelOp for Positioned<F> {
+    fn apply(&self, pixel: &mut BinaryPixel, rgb_max_value: u32) {
+        (self.0)(pixel, 0, 0, rgb_max_value)
+    }
+
+    fn apply_at(&self, pixel: &mut BinaryPixel, x: usize, y: usize, rgb_max_value: u32) {
+        (self.0)(pixel, x, y, rgb_max_value)
+    }
+}
+
+/*
+    Our Pipeline structure, a list of operations applied one after the other to each pixel.
+    The whole pipeline runs in a single read -> process -> write pass, without intermediate files.
+    Invert and Greyscale run on whole rows of raw bytes through the vectorised kernels, other operations pixel by pixel.
+
+    Pipeline::new().then(ImageProcess::Greyscale).then(ImageProcess::Brightness(20)).then(|pixel: &mut BinaryPixel, _max: u32| pixel.b = 0)
+*/
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    processes: Vec<(Arc<dyn PixelOp>, Option<Kernel>)>,
+}
+
+impl Pipeline {
+
+    // An empty pipeline, which copies pixels untouched.
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    // Add an operation (one of our processes or your own) at the end of the pipeline.
+    pub fn then<O: PixelOp + 'static>(mut self, process: O) -> Pipeline {
+        let kernel = match (&process as &dyn Any).downcast_ref::<ImageProcess>() {
+            Some(ImageProcess::Invert) => Some(Kernel::Invert),
+            Some(ImageProcess::Greyscale) => Some(Kernel::Greyscale),
+            _ => None,
+        };
+
+        self.processes.push((Arc::new(process), kernel));
+        self
+    }
+
+    // How many operations the pipeline holds.
+    pub fn len(&self) -> usize {
+        self.processes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processes.is_empty()
+    }
+
+    /*
+        Run every process of the pipeline on row y, in order.
+        On RGB rows, processes with a kernel run on the raw bytes and the ones in between share a single pass over the pixels.
+    */
+    pub(crate) fn apply_row(&self, row: &mut [u8], y: usize, layout: Layout, rgb_max_value: u32) {
+        let kernels = layout.color_samples == 3 && layout.depth == 3;
+
+        let mut start = 0;
+        while start < self.processes.len() {
+            if let (Some(kernel), true) = (self.processes[start].1, kernels) {
+                kernel.apply(row, rgb_max_value);
+                start += 1;
+                continue;
+            }
+
+            let end = match self.processes[start..].iter().position(|(_, kernel)| kernel.is_some() && kernels) {
+                Some(length) => start + length,
+                None => self.processes.len(),
+            };
+
+            for (bytes, x) in row.chunks_exact_mut(layout.pixel_bytes()).zip(0..) {
+
+                // Get a BinaryPixel struct from our position in buffer.
+                let mut pixel = BinaryPixel::read(bytes, layout);
+
+                // Transform pixel.
+                for (process, _) in self.processes[start..end].iter() {
+                    process.apply_at(&mut pixel, x, y, rgb_max_value);
+                }
+
+                // Overwrite pixels values in our buffer with new transformed values.
+                pixel.write(bytes, layout);
+            }
+
+            start = end;
+        }
+    }
+}
+
+// A single operation is a pipeline of its own.
+impl<O: PixelOp + 'static> From<O> for Pipeline {
+    fn from(process: O) -> Pipeline {
+        Pipeline::new().then(process)
+    }
+}
+
+impl<R: BufRead> BinaryImage<R> {
+
+    /*
+        process_stream(processor, writer, process)
+
+        Same as process_with(), straight from the reader's current position, which must be the pixels section: nothing is seeked.
+        This is the way to process images coming from stdin, a socket or an HTTP body. As their pixels can only be read once, the image is consumed.
+    */
+    pub fn process_stream<W: Write + Send, P: Into<Pipeline>>(self, processor: &Processor, writer: &mut W, process: P) -> Result<(), PpmError> {
+        self.process_stream_monitored(processor, writer, process, &Monitor::new())
+    }
+
+    // Same as process_stream(), but progress is reported to the monitor and the process can be cancelled through it.
+    pub fn process_stream_monitored<W: Write + Send, P: Into<Pipeline>>(
+        mut self,
+        processor: &Processor,
+        writer: &mut W,
+        process: P,
+        monitor: &Monitor,
+    ) -> Result<(), PpmError> {
+        let height = self.height;
+        self.write_processed(processor, writer, 0..height, process, monitor)
+    }
+
+    // Write the headers and the processed pixels, read from the reader's current position.
+    fn write_processed<W: Write + Send, P: Into<Pipeline>>(
+        &mut self,
+        processor: &Processor,
+        writer: &mut W,
+        rows: Range<usize>,
+        process: P,
+        monitor: &Monitor,
+    ) -> Result<(), PpmError> {
+
+        /*  Write headers to the output */
+        
+        write_header(writer, Kind::Pixmap, Format::Binary, (self.width, self.height), self.rgb_max_value, &self.comments)?;
+
+        process_pixels(
+            processor,
+            monitor,
+            &mut self.reader,
+            writer,
+            (self.width, self.height),
+            rows,
+            Layout::rgb(self.rgb_max_value),
+            self.rgb_max_value as u32,
+            process.into(),
+        )
+    }
+}
+
+impl<R: BufRead + Seek> BinaryImage<R> {
 
     /*
         process_and_output(filename, process)
@@ -58,278 +349,739 @@ impl BinaryImage {
         Process (transform) image and output it to a file.
 
         The first parameter is the output's filename.
-        The second parameter is one of ImageProcess enum's values, it tells wiich transformation that we want to apply.
+        The second parameter is one of ImageProcess enum's values, or a whole Pipeline of them, it tells wiich transformation that we want to apply.
 
         Return a Result with nothing if everything went smooth.
 
-        WARNING: this is certainly not thread safe when being called from any context. but it's using threads, so that's cool.
-    
+        Several images can be processed at the same time from different threads, each call uses its own buffer.
     */
-    pub fn process_and_output(&mut self, filename: &Path, process: ImageProcess) -> Result<(), Error> {
-        
-        /* 
-            We were forced to use an unsafe bloc here because the main buffer was not effectively
-            written over by our threads. No compile error, no runtime error, just nothing happening.
-            We still need to investigate the issue cause it seems really likely that we missed something
-            about memory and thread concurrency in Rust.
-
-            Still working on it. 
-        */
-        unsafe {
-
-            // Seek pixels section starting position.
-            match self.reader.seek(SeekFrom::Start(self.pixels_offset as u64)) {
-                Ok(file) => file,
-                Err(e) => return Err(e),
-            };
+    pub fn process_and_output<P: Into<Pipeline>>(&mut self, filename: &Path, process: P) -> Result<(), PpmError> {
+        self.process_and_output_monitored(filename, process, &Monitor::new())
+    }
 
-            // Create our output file.
-            let out_file = match File::create(filename) {
-                Ok(file) => file,
-                Err(e) => return Err(e),
-            };
+    /*
+        process_and_output_monitored(filename, process, monitor)
 
-            // Get a writer to the output file.
-            let mut writer = BufWriter::new(out_file);
+        Same as process_and_output(), but progress is reported to the monitor and the process can be cancelled through it.
+        A cancelled or failed process leaves no partial output file behind.
 
-            /*  Write headers to the output file */
-            
-            // Magic number
-            writer.write(self.magic_number.as_bytes()).unwrap();
-            writer.write(b"\n").unwrap();
-
-            // Width and height
-            writer.write(self.width.to_string().as_bytes()).unwrap();
-            writer.write(b" ").unwrap();
-            writer.write(self.height.to_string().as_bytes()).unwrap();
-            writer.write(b"\n").unwrap();
-
-            // RGB max value.
-            writer.write(self.rgb_max_value.to_string().as_bytes()).unwrap();
-            writer.write(b"\n").unwrap();
-            
-            // get right function for processing.
-            let func = match process {
-                ImageProcess::Invert => invert_binary_pixel,
-                ImageProcess::Greyscale => greyscale_binary_pixel,
-            };
+        Return a Result with nothing if everything went smooth, or PpmError::Cancelled.
+    */
+    pub fn process_and_output_monitored<P: Into<Pipeline>>(&mut self, filename: &Path, process: P, monitor: &Monitor) -> Result<(), PpmError> {
+        let height = self.height;
 
-            // the pixels section size in bytes.
-            let mut number_of_pixels_bytes = (self.width * self.height) * 3;
+        output_file(filename, |writer| self.process_rows_monitored(&Processor::default(), writer, 0..height, process, monitor))
+    }
 
-            /* 
-                How many threads we should be able to launch for one iteration of the main loop.
-                this is actually the number of logical processing units (threads) in our CPU and jere it is 
-                retrieved at runtime.
-            */
-            let cores = num_cpus::get();
+    /*
+        process_and_write(writer, process)
 
-            /* Here comes the main reading -> spawning threads -> processing -> writing loop. */
+        Same as process_and_output(), but the processed image goes to any writer: a file, stdout, a socket or a Vec<u8>.
+        The writer is not flushed, wrap it in a BufWriter for unbuffered destinations.
+    */
+    pub fn process_and_write<W: Write + Send, P: Into<Pipeline>>(&mut self, writer: &mut W, process: P) -> Result<(), PpmError> {
+        self.process_with(&Processor::default(), writer, process)
+    }
 
-            // read until there's no more pixel bytes.
-            while number_of_pixels_bytes > 0 {
+    /*
+        process_with(processor, writer, process)
 
-                /* 
-                    our main buffer where we do the processing.
-                    3 megabytes buffer seems to be a good starting point 
-                */
-                const PIXELS_BUFFER_BYTES_LENGTH: usize = 1024 * 1024 * 3;
-                static mut BUFFER: [u8; PIXELS_BUFFER_BYTES_LENGTH] = [0; PIXELS_BUFFER_BYTES_LENGTH];
+        Same as process_and_write(), but pixels are transformed on an existing Processor's workers.
+        Share a single Processor between many images to avoid spawning new threads for each of them.
+    */
+    pub fn process_with<W: Write + Send, P: Into<Pipeline>>(
+        &mut self,
+        processor: &Processor,
+        writer: &mut W,
+        process: P,
+    ) -> Result<(), PpmError> {
+        let height = self.height;
+        self.process_rows_with(processor, writer, 0..height, process)
+    }
 
-                // compute how many bytes we must read.
-                // read size of our buffer if there's more or equal to our buffer size in file.
-                let bytes_read = if number_of_pixels_bytes >= PIXELS_BUFFER_BYTES_LENGTH {
-                    PIXELS_BUFFER_BYTES_LENGTH
-                }
-                // else, read what's left in file.
-                else {
-                    number_of_pixels_bytes
-                };
+    /*
+        process_rows_with(processor, writer, rows, process)
 
-                for i in 0..PIXELS_BUFFER_BYTES_LENGTH {
-                    BUFFER[i] = 0;
-                }
+        Same as process_with(), but only the pixels of the given rows are transformed, the other ones are copied untouched.
+        The whole image is still written.
+    */
+    pub fn process_rows_with<W: Write + Send, P: Into<Pipeline>>(
+        &mut self,
+        processor: &Processor,
+        writer: &mut W,
+        rows: Range<usize>,
+        process: P,
+    ) -> Result<(), PpmError> {
+        self.process_rows_monitored(processor, writer, rows, process, &Monitor::new())
+    }
 
-                // fill the buffer from our file
-                match self.reader.read_exact(&mut BUFFER[..bytes_read]) {
-                    Ok(read) => read,
-                    Err(e) => {
-                        return Err(e);
-                    }
-                };
+    /*
+        process_monitored(processor, writer, process, monitor)
 
-                // How many pixels did we just read ?
-                let pixels_read = bytes_read / 3;
+        Same as process_with(), but progress is reported to the monitor and the process can be cancelled through it.
+        On cancellation the writer is left with a partial image.
 
-                // We need to compute how many threads we must spawn
-                let to_spawn = if pixels_read < cores {
-                    pixels_read
-                } else {
-                    cores
-                };
+        Return a Result with nothing if everything went smooth, or PpmError::Cancelled.
+    */
+    pub fn process_monitored<W: Write + Send, P: Into<Pipeline>>(
+        &mut self,
+        processor: &Processor,
+        writer: &mut W,
+        process: P,
+        monitor: &Monitor,
+    ) -> Result<(), PpmError> {
+        let height = self.height;
+        self.process_rows_monitored(processor, writer, 0..height, process, monitor)
+    }
 
-                // How many pixels each thread should work with ?
-                // This will be used for buffer slicing inside the thread closure.
-                let pixels_per_thread = pixels_read / to_spawn;
-
-                // This vector will hold our thread handlers to be used after spawning with handler[i]join().
-                let mut handles = vec![];
-
-                // spawn our threads and push handlers to our vec.
-                for i in 0..to_spawn {
-                    
-                    handles.push(std::thread::spawn(move || {
-                        
-                        // Get a buffer's slice for each thread.
-                        let slice = &mut BUFFER
-                            [(i * (pixels_per_thread * 3))..((i + 1) * (pixels_per_thread * 3))];
-
-                        // For each 3 bytes in the slice, which is 1 pixel.
-                        for y in 0..(slice.len() / 3) {
-
-                            // Get a BinaryPixel struct from our position in buffer.
-                            let mut pixel = BinaryPixel {
-                                r: slice[y * 3] as u32,
-                                g: slice[(y * 3) + 1] as u32,
-                                b: slice[(y * 3) + 2] as u32,
-                            };
-
-                            // Transform pixel.
-                            func(&mut pixel);
-
-                            // Overwrite pixels values in our buffer with new transformed values.
-                            slice[y * 3] = pixel.r as u8;
-                            slice[(y * 3) + 1] = pixel.g as u8;
-                            slice[(y * 3) + 2] = pixel.b as u8;
-                        }
-                    }));
-                }
+    fn process_rows_monitored<W: Write + Send, P: Into<Pipeline>>(
+        &mut self,
+        processor: &Processor,
+        writer: &mut W,
+        rows: Range<usize>,
+        process: P,
+        monitor: &Monitor,
+    ) -> Result<(), PpmError> {
+
+        // Seek pixels section starting position.
+        self.reader.seek(SeekFrom::Start(self.pixels_offset as u64))?;
+
+        self.write_processed(processor, writer, rows, process, monitor)
+    }
+
+    /*
+        rows()
+
+        Iterate over the image one row at a time, without loading it whole.
+        Each row knows its y coordinate, the x coordinate of a pixel is its index in the row.
+
+        Will return Result with Err if the reader can't seek to the pixels section, reading errors come with each row.
+    */
+    pub fn rows(&mut self) -> Result<Rows<'_, R>, PpmError> {
+        self.reader.seek(SeekFrom::Start(self.pixels_offset as u64))?;
+
+        let layout = Layout::rgb(self.rgb_max_value);
+        Ok(Rows {
+            buffer: vec![0; self.width * layout.pixel_bytes()],
+            image: self,
+            layout,
+            y: 0,
+        })
+    }
+
+    /*
+        filter_and_output(filename, filter)
+
+        Apply a neighborhood operation (a blur, an edge detection...) and output the image to a file.
+        Pixels are streamed as bands of complete rows, so huge images can be filtered without being loaded.
+    */
+    pub fn filter_and_output<F: NeighborhoodOp + 'static>(&mut self, filename: &Path, filter: F) -> Result<(), PpmError> {
+        self.filter_and_output_monitored(filename, filter, &Monitor::new())
+    }
+
+    // Same as filter_and_output(), but progress is reported to the monitor and the filter can be cancelled through it. No partial output file is left behind.
+    pub fn filter_and_output_monitored<F: NeighborhoodOp + 'static>(&mut self, filename: &Path, filter: F, monitor: &Monitor) -> Result<(), PpmError> {
+        output_file(filename, |writer| self.filter_monitored(&Processor::default(), writer, filter, monitor))
+    }
+
+    // Same as filter_and_output(), but the filtered image goes to any writer, which is not flushed.
+    pub fn filter_and_write<W: Write + Send, F: NeighborhoodOp + 'static>(&mut self, writer: &mut W, filter: F) -> Result<(), PpmError> {
+        self.filter_with(&Processor::default(), writer, filter)
+    }
+
+    // Same as filter_and_write(), on an existing Processor's workers.
+    pub fn filter_with<W: Write + Send, F: NeighborhoodOp + 'static>(
+        &mut self,
+        processor: &Processor,
+        writer: &mut W,
+        filter: F,
+    ) -> Result<(), PpmError> {
+        self.filter_monitored(processor, writer, filter, &Monitor::new())
+    }
+
+    // Same as filter_with(), but progress is reported to the monitor and the filter can be cancelled through it, just like process_monitored().
+    pub fn filter_monitored<W: Write + Send, F: NeighborhoodOp + 'static>(
+        &mut self,
+        processor: &Processor,
+        writer: &mut W,
+        filter: F,
+        monitor: &Monitor,
+    ) -> Result<(), PpmError> {
+
+        // Seek pixels section starting position.
+        self.reader.seek(SeekFrom::Start(self.pixels_offset as u64))?;
+
+        write_header(writer, Kind::Pixmap, Format::Binary, (self.width, self.height), self.rgb_max_value, &self.comments)?;
+
+        process_bands(
+            processor,
+            monitor,
+            &mut self.reader,
+            writer,
+            self.width,
+            self.height,
+            Layout::rgb(self.rgb_max_value),
+            self.rgb_max_value as u32,
+            Arc::new(filter),
+        )
+    }
+
+    /*
+        load()
+
+        Read the whole pixels section into an in-memory Image.
+        Only use this when the image fits in RAM, process_and_output() is the way to go for huge files.
+
+        Return a Result with the Image, or the reading error.
+    */
+    pub fn load(&mut self) -> Result<Image, PpmError> {
+
+        self.reader.seek(SeekFrom::Start(self.pixels_offset as u64))?;
+
+        let bytes = self.read_pixels()?;
+        let pixels = self.decode_pixels(&bytes, 0)?;
+
+        Ok(Image {
+            pixels,
+            width: self.width,
+            height: self.height,
+            max_value: self.rgb_max_value as u16,
+            format: Format::Binary,
+            kind: Kind::Pixmap,
+            comments: self.comments.clone(),
+        })
+    }
+
+    // How many bytes a single sample takes in the pixels section.
+    pub fn bytes_per_sample(&self) -> usize {
+        bytes_per_sample(self.rgb_max_value)
+    }
+
+    // Get the pixel at (x, y), reading only its bytes from the file.
+    pub fn get_pixel(&mut self, x: usize, y: usize) -> Result<Pixel, PpmError> {
+        Ok(self.read_region(x, y, 1, 1)?.pixels[0])
+    }
+
+    /*
+        read_region(x, y, width, height)
+
+        Read a rectangle of the image into an in-memory Image, seeking straight to each of its rows.
+        Only the region's bytes are read, so tiles of huge images can be sampled quickly.
+
+        Will return Result with Err if the region doesn't fit inside the image, if the file is too short, or if a sample is above the max value.
+    */
+    pub fn read_region(&mut self, x: usize, y: usize, width: usize, height: usize) -> Result<Image, PpmError> {
+        self.check_region(x, y, width, height)?;
+
+        let layout = Layout::rgb(self.rgb_max_value);
+        let mut bytes = vec![0; width * layout.pixel_bytes()];
+        let mut pixels = Vec::with_capacity(width * height);
+
+        for row in y..y + height {
+            let offset = ((row * self.width) + x) * layout.pixel_bytes();
+            self.reader.seek(SeekFrom::Start((self.pixels_offset + offset) as u64))?;
+
+            let filled = fill_buffer(&mut self.reader, &mut bytes)?;
+            if filled < bytes.len() {
+                let expected = (self.width * self.height) * 3;
+                let got = (offset + filled) / self.bytes_per_sample();
+                return Err(PpmError::TruncatedPixelData { expected, got });
+            }
+
+            pixels.extend(self.decode_pixels(&bytes, offset)?);
+        }
+
+        Ok(Image {
+            pixels,
+            width,
+            height,
+            max_value: self.rgb_max_value as u16,
+            format: Format::Binary,
+            kind: Kind::Pixmap,
+            comments: self.comments.clone(),
+        })
+    }
+
+    /*
+        write_region(writer, x, y, region)
+
+        Write an in-memory Image over the rectangle starting at (x, y), seeking straight to each of its rows.
+        Our reader is read-only, so the writer must be this same image opened for writing (or a copy of it).
+        The region is rescaled when its max value is not the image's one.
+
+        Will return Result with Err if the region doesn't fit inside the image.
+    */
+    pub fn write_region<W: Write + Seek>(&mut self, writer: &mut W, x: usize, y: usize, region: &Image) -> Result<(), PpmError> {
+        self.check_region(x, y, region.width, region.height)?;
+
+        let layout = Layout::rgb(self.rgb_max_value);
+        let mut bytes = vec![0; region.width * layout.pixel_bytes()];
 
-                // Wait for threads to finish before next iteration.
-                for handle in handles {
-                    handle.join().unwrap();
+        for (row, pixels) in (y..).zip(region.pixels.chunks_exact(region.width.max(1))) {
+            for (bytes, pixel) in bytes.chunks_exact_mut(layout.pixel_bytes()).zip(pixels) {
+                let mut pixel = *pixel;
+                if region.max_value as usize != self.rgb_max_value {
+                    pixel.rescale(region.max_value, self.rgb_max_value as u16);
                 }
 
-                // Write the transformed buffer to the output file
-                match writer.write_all(&mut BUFFER[..bytes_read]) {
-                    Ok(write) => write,
-                    Err(e) => { return Err(e); }
+                BinaryPixel {
+                    r: pixel.red as u32,
+                    g: pixel.green as u32,
+                    b: pixel.blue as u32,
                 }
+                .write(bytes, layout);
+            }
+
+            let offset = ((row * self.width) + x) * layout.pixel_bytes();
+            writer.seek(SeekFrom::Start((self.pixels_offset + offset) as u64))?;
+            writer.write_all(&bytes)?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Decode pixels read at offset (in bytes) in the pixels section, samples above the max value are refused.
+    fn decode_pixels(&mut self, bytes: &[u8], offset: usize) -> Result<Vec<Pixel>, PpmError> {
+        let mut samples = vec![0; bytes.len() / self.bytes_per_sample()];
+        let max_value = self.rgb_max_value;
 
-                // Substract all bytes that were processed.
-                number_of_pixels_bytes = number_of_pixels_bytes - bytes_read;
+        decode_samples(bytes, max_value, || self.position_at(offset), &mut samples)?;
+
+        Ok(samples.chunks_exact(3).map(|rgb| Pixel { red: rgb[0], green: rgb[1], blue: rgb[2] }).collect())
+    }
+
+    // Line and column of the byte at offset in the pixels section, found by reading the section up to it. Only needed for error messages.
+    fn position_at(&mut self, offset: usize) -> Result<(usize, usize), PpmError> {
+        self.reader.seek(SeekFrom::Start(self.pixels_offset as u64))?;
+
+        let mut position = self.position;
+        let mut buffer = vec![0; offset.min(PIXELS_BUFFER_BYTES_LENGTH)];
+        let mut left = offset;
+
+        while left > 0 {
+            let length = left.min(buffer.len());
+            let filled = fill_buffer(&mut self.reader, &mut buffer[..length])?;
+            position = position_after(position, &buffer[..filled]);
+
+            if filled < length {
+                break;
             }
+            left -= length;
+        }
 
-            Ok(())
+        Ok(position)
+    }
+
+    fn check_region(&self, x: usize, y: usize, width: usize, height: usize) -> Result<(), PpmError> {
+        let outside = |start: usize, length: usize, size: usize| start.checked_add(length).is_none_or(|end| end > size);
+
+        if outside(x, width, self.width) || outside(y, height, self.height) {
+            return Err(PpmError::RegionOutOfBounds { x, y, width, height });
+        }
+
+        Ok(())
+    }
+
+    /*
+        Read the whole pixels section, just like read_exact() would,
+        but hitting the end of file early is reported as a truncated pixels section.
+        The buffer grows with what is actually read, a lying header can't make us allocate a huge image upfront.
+    */
+    fn read_pixels(&mut self) -> Result<Vec<u8>, PpmError> {
+        let length = (self.width * self.height) * Layout::rgb(self.rgb_max_value).pixel_bytes();
+        let bytes = read_bytes(&mut self.reader, length)?;
+
+        if bytes.len() < length {
+            let expected = (self.width * self.height) * 3;
+            let got = bytes.len() / self.bytes_per_sample();
+            return Err(PpmError::TruncatedPixelData { expected, got });
+        }
+
+        Ok(bytes)
+    }
+}
+
+// A single row of pixels, returned by BinaryImage::rows().
+#[derive(Clone, Debug, PartialEq)]
+pub struct Row {
+    pub y: usize,
+    pub pixels: Vec<Pixel>,
+}
+
+// Our Rows iterator, returned by BinaryImage::rows(). Iteration stops after the first error.
+pub struct Rows<'a, R: BufRead + Seek> {
+    image: &'a mut BinaryImage<R>,
+    layout: Layout,
+    buffer: Vec<u8>,
+    y: usize,
+}
+
+impl<R: BufRead + Seek> Iterator for Rows<'_, R> {
+    type Item = Result<Row, PpmError>;
+
+    fn next(&mut self) -> Option<Result<Row, PpmError>> {
+        if self.y >= self.image.height {
+            return None;
         }
+
+        let filled = match fill_buffer(&mut self.image.reader, &mut self.buffer) {
+            Ok(filled) => filled,
+            Err(e) => {
+                self.y = self.image.height;
+                return Some(Err(e));
+            }
+        };
+
+        if filled < self.buffer.len() {
+            let got = (self.y * self.buffer.len() + filled) / self.layout.sample_bytes;
+            self.y = self.image.height;
+            return Some(Err(PpmError::TruncatedPixelData { expected: self.image.width * self.image.height * 3, got }));
+        }
+
+        let pixels = match self.image.decode_pixels(&self.buffer, self.y * self.buffer.len()) {
+            Ok(pixels) => pixels,
+            Err(e) => {
+                self.y = self.image.height;
+                return Some(Err(e));
+            }
+        };
+
+        self.y += 1;
+        Some(Ok(Row { y: self.y - 1, pixels }))
     }
 }
 
 /*
+    process_pixels(processor, monitor, reader, writer, (width, height), rows, layout, rgb_max_value, pipeline)
 
-    This function is used by new_with_file_bin()
+    Read pixels from the reader, transform the ones in rows with the pipeline on the processor's workers, and write them all to the writer.
+    This is shared by every bufferized image (P6 and PAM), the layout tells how to find the samples to transform.
+    Chunks hold complete rows, so each pixel is transformed knowing its (x, y) coordinates.
+    Progress is reported to the monitor after each written chunk, and reading stops if it gets cancelled.
 
-    It provides a way to get the next header string when parsing a .ppm file.
-    It takes the reader the as input and will be seeking until the next section (header or pixels beginning offset)
+    Return a Result with nothing if everything went smooth, or PpmError::Cancelled.
+*/
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn process_pixels<R: Read, W: Write + Send>(
+    processor: &Processor,
+    monitor: &Monitor,
+    reader: &mut R,
+    writer: &mut W,
+    (width, height): (usize, usize),
+    rows: Range<usize>,
+    layout: Layout,
+    rgb_max_value: u32,
+    pipeline: Pipeline,
+) -> Result<(), PpmError> {
+
+    // Size of a single row, 16 bits images use two bytes per sample.
+    let row_bytes = width * layout.pixel_bytes();
+
+    // Every job needs the pipeline, it is shared rather than cloned.
+    let pipeline = Arc::new(pipeline);
+
+    stream_chunks(processor, monitor, writer, (height, row_bytes), |chunk_rows, chunk| {
+
+        // fill the chunk from our file, hitting the end of file early means the pixels section is truncated.
+        let filled = fill_buffer(reader, chunk)?;
+        if filled < chunk.len() {
+            return Err(PpmError::TruncatedPixelData {
+                expected: width * height * layout.depth,
+                got: (chunk_rows.start * row_bytes + filled) / layout.sample_bytes,
+            });
+        }
 
-    Returns the header that was found, or will error out.
-    
-    WARNING: Using it after all headers were read will result in undefined behavior !
+        let pipeline = Arc::clone(&pipeline);
+        let rows = rows.clone();
+        Ok(move |mut chunk: Vec<u8>| {
+            transform_chunk(&mut chunk, width, chunk_rows.start, &rows, layout, rgb_max_value, &pipeline);
+            chunk
+        })
+    })
+}
 
+/* 
+    3 megabytes seems to be a good starting point for a buffer, it is split into one chunk per worker.
+    Each chunk is owned by a single stage (reading, processing or writing) at a time.
 */
+pub(crate) const PIXELS_BUFFER_BYTES_LENGTH: usize = 1024 * 1024 * 3;
 
-fn get_next_header(reader: &mut BufReader<File>) -> Result<String, Error> {
-    
-    // we should not need more than this static size buffer to parse a single header value.
-    let mut buffer: [u8; 8192] = [0; 8192];
-    let mut index: usize = 0;
-    let mut end_of_header: usize = 0;
-
-    let delimiters = vec!['\n', '\r', ' ', '\t'];
-    let comment_delimiters = vec!['\n', '\r'];
-
-    let parse_error = |msg: &str| Error::new(ErrorKind::InvalidInput, msg);
-
-    // Get current offset in reader
-    let current = match reader.seek(SeekFrom::Current(0)) {
-        Ok(position) => position,
-        Err(e) => {
-            let msg = format!("Couldn't get current position ({}).", e.to_string());
-            return Err(parse_error(&msg));
-        }
-    };
-
-    // Fill buffer from file.
-    match reader.read(&mut buffer[..]) {
-        Ok(read) => read,
-        Err(e) => {
-            let msg = format!("Couldn't read from file ({}).", e.to_string());
-            return Err(parse_error(&msg));
-        }
-    };
-    // Find next whitespace
-    for byte in buffer.iter() {
-        let c = *byte as char;
-        if delimiters.contains(&c) {
-            end_of_header = index;
-            break;
-        };
+// A transformed chunk with its reading index, None when the transformation panicked.
+pub(crate) type ProcessedChunk = (usize, Option<Vec<u8>>);
+
+/*
+    stream_chunks(processor, monitor, writer, (height, row_bytes), fill)
 
-        index = index + 1;
+    Run a reading -> processing -> writing pipeline over the rows of a pixels section, the engine behind every streaming process.
+    Rows are split into chunks of complete rows, fill is called on the caller's thread with each chunk's rows, in order, and a buffer of their size.
+    It fills the buffer and returns the transformation to run on it: workers transform the previous chunks while the next one is filled,
+    and a writer thread outputs the transformed ones in reading order.
+
+    Progress is reported to the monitor as chunks are written, counted in binary pixels bytes (height * row_bytes in total), whatever the output is.
+    Once the monitor is cancelled, no more chunks are filled.
+
+    Return a Result with nothing if everything went smooth, or the first reading or writing error.
+*/
+pub(crate) fn stream_chunks<W, F, T>(
+    processor: &Processor,
+    monitor: &Monitor,
+    writer: &mut W,
+    (height, row_bytes): (usize, usize),
+    mut fill: F,
+) -> Result<(), PpmError>
+where
+    W: Write + Send,
+    F: FnMut(Range<usize>, &mut Vec<u8>) -> Result<T, PpmError>,
+    T: FnOnce(Vec<u8>) -> Vec<u8> + Send + 'static,
+{
+    if row_bytes == 0 {
+        return Ok(());
     }
 
-    // Parse header to string
-    let header_buffer = &buffer[..end_of_header];
-    let header_string = match String::from_utf8(header_buffer.to_vec()) {
-        Ok(s) => s.to_string(),
-        Err(_e) => return Err(parse_error("Couldn't convert header to string.")),
-    };
+    // Each chunk holds a whole number of rows.
+    let chunk_rows = (PIXELS_BUFFER_BYTES_LENGTH / processor.threads() / row_bytes).max(1);
+    let progress = ChunksProgress { chunk_bytes: (chunk_rows * row_bytes) as u64, total: (height * row_bytes) as u64 };
 
-    // Get a buffer's slice after the header (starting at the fist whitespace character)
-    let after_header = &buffer[end_of_header..];
-    index = end_of_header;
+    /*
+        Triple buffering: one buffer being read, one being processed and one being written.
+        Chunks come back from the writer once written, reading waits for one when they are all in use.
+    */
+    let (free_sender, free_chunks) = mpsc::channel::<Vec<u8>>();
+    for _ in 0..processor.threads() * 3 {
+        free_sender.send(vec![]).unwrap();
+    }
 
-    // Ignore comments, get to the next line.
-    if header_string.starts_with('#') {
+    let (processed_sender, processed_chunks) = mpsc::channel::<ProcessedChunk>();
 
-        // iterate until we find a newline (CR/LF) character.
-        for byte in after_header.iter() {
-            let c = *byte as char;
+    std::thread::scope(|scope| {
 
-            if comment_delimiters.contains(&c) {
-                match reader.seek(SeekFrom::Start(current + 1 + (index as u64))) {
-                    Ok(position) => position,
-                    Err(_e) => return Err(parse_error("Couldn't seek to new position.")),
-                };
+        let writing = scope.spawn(move || write_chunks(writer, processed_chunks, free_sender, monitor, progress));
 
-                // recurse function cause we just read a comment.
-                return get_next_header(reader);
+        /* Here comes the main reading -> processing loop. */
+
+        let mut read = 0;
+        let mut chunk_start = 0;
+
+        // read until there's no more rows.
+        while chunk_start < height {
+
+            // This is where a cancelled process stops.
+            if monitor.is_cancelled() {
+                return Err(PpmError::Cancelled);
             }
 
-            index = index + 1;
+            // Wait for a free chunk, if the writer is gone it failed and there's no point reading more.
+            let mut chunk = match free_chunks.recv() {
+                Ok(chunk) => chunk,
+                Err(_) => break,
+            };
+
+            // a whole chunk if there's enough rows left, else what's left.
+            let chunk_end = (chunk_start + chunk_rows).min(height);
+            chunk.clear();
+            chunk.resize((chunk_end - chunk_start) * row_bytes, 0);
+
+            let transform = fill(chunk_start..chunk_end, &mut chunk)?;
+            submit_chunk(processor, &processed_sender, read, chunk, transform);
+
+            chunk_start = chunk_end;
+            read += 1;
         }
 
-        return Err(parse_error("Couldn't find end of comment."));
+        // Let the writer know no more chunks are coming, once the workers are done with theirs.
+        drop(processed_sender);
+
+        // A panicking writer is reported just like a panicking transformation.
+        let written = writing.join().unwrap_or(Err(PpmError::ProcessingFailed))?;
+        if written != read {
+            return Err(PpmError::ProcessingFailed);
+        }
+
+        Ok(())
+    })
+}
+
+// How progress is counted while chunks are written: all of them are chunk_bytes long, except the last one.
+#[derive(Copy, Clone)]
+struct ChunksProgress {
+    chunk_bytes: u64,
+    total: u64,
+}
+
+// Hand a chunk to the processor's workers, the transformed chunk goes to the writer with its reading index.
+pub(crate) fn submit_chunk<F>(processor: &Processor, processed_chunks: &Sender<ProcessedChunk>, index: usize, chunk: Vec<u8>, transform: F)
+where
+    F: FnOnce(Vec<u8>) -> Vec<u8> + Send + 'static,
+{
+    let sender = processed_chunks.clone();
+
+    processor.execute(move || {
+        let transformed = panic::catch_unwind(AssertUnwindSafe(|| transform(chunk)));
+        let _ = sender.send((index, transformed.ok()));
+    });
+}
+
+// Transform in place the pixels of a chunk which are in rows, the chunk starts at row first_row.
+pub(crate) fn transform_chunk(
+    chunk: &mut [u8],
+    width: usize,
+    first_row: usize,
+    rows: &Range<usize>,
+    layout: Layout,
+    rgb_max_value: u32,
+    pipeline: &Pipeline,
+) {
+    for (row, y) in chunk.chunks_exact_mut(width * layout.pixel_bytes()).zip(first_row..) {
+        if rows.contains(&y) {
+            pipeline.apply_row(row, y, layout, rgb_max_value);
+        }
     }
+}
 
-    // Get to the next header offset in reader.
-    for byte in after_header.iter() {
-        let c = *byte as char;
+/*
+    Writer stage of stream_chunks(), it puts transformed chunks back in reading order before writing them.
+    Written chunks are handed back for reading.
 
-        // Stop if we just met a non-whitespace character.
-        if !delimiters.contains(&c) {
-            match reader.seek(SeekFrom::Start(current + (index as u64))) {
-                Ok(_position) => { break; }
-                Err(_e) => return Err(parse_error("Couldn't seek to new position.")),
-            }
+    Return a Result with how many chunks were written, or PpmError::ProcessingFailed at the first chunk whose transformation panicked.
+*/
+fn write_chunks<W: Write>(
+    writer: &mut W,
+    processed_chunks: Receiver<ProcessedChunk>,
+    free_chunks: Sender<Vec<u8>>,
+    monitor: &Monitor,
+    progress: ChunksProgress,
+) -> Result<usize, PpmError> {
+
+    let mut pending = BTreeMap::new();
+    let mut written = 0;
+
+    for (index, chunk) in processed_chunks {
+        match chunk {
+            Some(chunk) => pending.insert(index, chunk),
+            None => return Err(PpmError::ProcessingFailed),
         };
 
-        index = index + 1;
+        while let Some(chunk) = pending.remove(&written) {
+            writer.write_all(&chunk)?;
+            written += 1;
+
+            let done = (written as u64 * progress.chunk_bytes).min(progress.total);
+            monitor.report(Progress { done, total: progress.total });
+
+            let _ = free_chunks.send(chunk);
+        }
     }
 
-    Ok(header_string)
+    Ok(written)
 }
 
+// Fill the buffer from the reader like read_exact() would, but return how many bytes were read if the end of file comes first.
+pub(crate) fn fill_buffer<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<usize, PpmError> {
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(PpmError::Io(e)),
+        }
+    }
+
+    Ok(filled)
+}
+
+/*
+    Create a file and write it through a buffered writer, which is flushed.
+    On any error (a cancellation, truncated input, a full disk...) the partial file is removed, and the original error returned.
+*/
+pub(crate) fn output_file<F>(filename: &Path, write: F) -> Result<(), PpmError>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), PpmError>,
+{
+    let mut writer = BufWriter::new(File::create(filename)?);
+    let result = write(&mut writer).and_then(|_| Ok(writer.flush()?));
+
+    drop(writer);
+    discard_on_error(filename, result)
+}
+
+// Remove a partial output file if result is an Err, a file which can't be removed is left behind but the original error is kept.
+pub(crate) fn discard_on_error(filename: &Path, result: Result<(), PpmError>) -> Result<(), PpmError> {
+    if result.is_err() {
+        let _ = std::fs::remove_file(filename);
+    }
+    result
+}
+
+// Read up to length bytes. The buffer grows with what is actually read, so a header announcing a huge image doesn't allocate it upfront.
+pub(crate) fn read_bytes<R: Read>(reader: &mut R, length: usize) -> Result<Vec<u8>, PpmError> {
+    let mut bytes = vec![];
+    reader.take(length as u64).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Samples are one byte up to 255 RGB max value, two big-endian bytes above.
+pub(crate) fn bytes_per_sample(rgb_max_value: usize) -> usize {
+    if rgb_max_value < 256 {
+        1
+    } else {
+        2
+    }
+}
+
+/*
+    decode_samples(bytes, max_value, start, samples)
+
+    Decode binary samples, each one is 1 byte or 2 big-endian bytes above a max value of 255.
+    This is shared by every reader of binary samples (P5, P6, conversions, random access...), so they all refuse the same files.
+    start gives the line and column of bytes' first byte in the file, it is only called when a sample is refused.
+
+    Will return Result with Err(InvalidSample) at the first sample above the max value.
+*/
+pub(crate) fn decode_samples<F>(bytes: &[u8], max_value: usize, start: F, samples: &mut [u16]) -> Result<(), PpmError>
+where
+    F: FnOnce() -> Result<(usize, usize), PpmError>,
+{
+    let sample_bytes = bytes_per_sample(max_value);
+
+    for (index, (sample, value)) in samples.iter_mut().zip(bytes.chunks_exact(sample_bytes)).enumerate() {
+        *sample = match value {
+            [byte] => *byte as u16,
+            _ => u16::from_be_bytes([value[0], value[1]]),
+        };
+
+        if *sample as usize > max_value {
+            let (line, column) = position_after(start()?, &bytes[..index * sample_bytes]);
+            return Err(PpmError::InvalidSample { line, column });
+        }
+    }
+
+    Ok(())
+}
+
+//  Function that writes an in-memory image in binary mode (P6).
+pub fn write_image<W: Write>(image: &Image, writer: &mut W) -> Result<(), PpmError> {
+    write_header(writer, Kind::Pixmap, Format::Binary, (image.width, image.height), image.max_value as usize, &image.comments)?;
+
+    let layout = Layout::rgb(image.max_value as usize);
+    let pixel_bytes = layout.pixel_bytes();
+    let mut bytes = [0; 6];
+
+    for pixel in image.pixels.iter() {
+        let binary_pixel = BinaryPixel {
+            r: pixel.red as u32,
+            g: pixel.green as u32,
+            b: pixel.blue as u32,
+        };
+
+        binary_pixel.write(&mut bytes[..pixel_bytes], layout);
+        writer.write_all(&bytes[..pixel_bytes])?;
+    }
+
+    Ok(())
+}
 
 /*
     new_with_file_bin(filename)
@@ -341,122 +1093,60 @@ fn get_next_header(reader: &mut BufReader<File>) -> Result<String, Error> {
     Will return Result with Err if an error occurs.
 */
 
-pub fn new_with_file_bin(filename: &Path) -> Result<BinaryImage, Error> {
-
-    let file_error = |msg: &str| Error::new(ErrorKind::InvalidInput, msg);
+pub fn new_with_file_bin(filename: &Path) -> Result<BinaryImage, PpmError> {
 
-    let file = match File::open(filename) {
-        Ok(file) => file,
-        Err(e) => {
-            let msg = format!(
-                "Could not read input .ppm file ({}).",
-                e.to_string()
-            );
-            return Err(file_error(&msg));
-        },
-    };
+    let file = File::open(filename)?;
+    new_with_reader_bin(BufReader::new(file))
+}
 
-    let mut reader = BufReader::new(file);
+/*
+    new_with_reader_bin(reader)
 
-    // Create our struct by parsing headers in the right order.
-    let image = BinaryImage {
+    Same as new_with_file_bin(), for any seekable reader such as a Cursor over bytes already in memory.
+    The pixels offset is relative to the reader's start.
+*/
+pub fn new_with_reader_bin<R: BufRead + Seek>(mut reader: R) -> Result<BinaryImage<R>, PpmError> {
 
-        // Get magic number
-        magic_number: match get_next_header(&mut reader) {
-            Ok(magic_number) => {
+    let start = reader.stream_position()? as usize;
 
-                // Check if value is indeed P6.
-                if magic_number != "P6" {
-                    return Err(file_error("Binary PPM must have P6 as a magic number."));
-                }
-                magic_number
-            }
+    let mut image = new_with_stream_bin(reader)?;
+    image.pixels_offset += start;
 
-            Err(e) => return Err(e),
-        },
-
-        // Get width header
-        width: match get_next_header(&mut reader) {
-            Ok(width_string) => match width_string.parse::<usize>() {
-                Ok(parsed) => parsed,
-                Err(e) => {
-                    let msg = format!(
-                        "Could not parse width header into a number ({}).",
-                        e.to_string()
-                    );
-                    return Err(file_error(&msg));
-                }
-            },
+    Ok(image)
+}
 
-            Err(e) => return Err(e),
-        },
-
-        // Get height header
-        height: match get_next_header(&mut reader) {
-            Ok(height_string) => match height_string.parse::<usize>() {
-                Ok(parsed) => parsed,
-                Err(e) => {
-                    let msg = format!(
-                        "Could not parse height header into a number ({}).",
-                        e.to_string()
-                    );
-                    return Err(file_error(&msg));
-                }
-            },
+/*
+    new_with_stream_bin(reader)
 
-            Err(e) => return Err(e),
-        },
-
-        // Get RGB max value
-        rgb_max_value: match get_next_header(&mut reader) {
-            Ok(rgb_max_value_string) => {
-                let parsed = match rgb_max_value_string.parse::<usize>() {
-                    Ok(parsed) => { 
-
-                        // Check if value is 255.
-                        if parsed != 255 {
-                            let msg = format!(
-                                "Only 24 bits pixels format is supported for now ({} as RGB_MAX_VALUE).",
-                                parsed.to_string()
-                            );
-                            return Err(file_error(&msg));
-                        }
-                        parsed 
-                    },
-                    Err(e) => {
-
-                        let msg = format!(
-                            "Could not parse RGB max value header into a number ({}).",
-                            e.to_string()
-                        );
-                        return Err(file_error(&msg));
-                    }
-                };
+    Same as new_with_reader_bin(), for readers which can't seek: stdin, a socket or an HTTP body.
+    Only the headers are read, the reader is left at the pixels section, ready for process_stream().
+    The pixels offset is relative to the reader's position when it was given.
+*/
+pub fn new_with_stream_bin<R: BufRead>(mut reader: R) -> Result<BinaryImage<R>, PpmError> {
 
-                if parsed != 255 {
-                    return Err(file_error("Only 255 RGB max value is supported for now."));
-                } else {
-                    parsed
-                }
-            }
+    // Parse headers and check if magic number is indeed P6.
+    let mut tokens = Tokenizer::new(&mut reader);
+    let header = read_header_tokens(&mut tokens)?;
+    let magic_number = header.kind.magic_number(header.format).to_string();
 
-            Err(e) => return Err(e),
-        },
+    if (header.kind, header.format) != (Kind::Pixmap, Format::Binary) {
+        return Err(PpmError::BadMagic(magic_number));
+    }
 
-        // Pixel offset should be right after the last header. 
-        // Whitespace is supposed to be consumed as well so we can just seek from current position to get the pixels section offset.
-        pixels_offset: match reader.seek(SeekFrom::Current(0)) {
-            Ok(offset) => offset as usize,
-            Err(e) => {
-                let msg = format!("Couldn't get pixels offset ({}).", e.to_string());
-                return Err(file_error(&msg));
-            }
-        },
+    // Pixel offset should be right after the last header, its single whitespace character was consumed as well.
+    let pixels_offset = tokens.offset();
+    let position = tokens.position();
 
+    Ok(BinaryImage {
         reader,
-    };
-
-    Ok(image)
+        magic_number,
+        pixels_offset,
+        height: header.height,
+        width: header.width,
+        rgb_max_value: header.max_value,
+        comments: header.comments,
+        position,
+    })
 }
 
 // Module for testing and benchmarking
@@ -478,11 +1168,11 @@ mod bench {
         let in_file_path = get_test_file_path();
         let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/invert.test.ppm"));
 
-        let mut img = new_with_file_bin(&in_file_path).unwrap();
-        img.process_and_output(&out_file_path, ImageProcess::Invert).unwrap();
+        let mut img = new_with_file_bin(in_file_path).unwrap();
+        img.process_and_output(out_file_path, ImageProcess::Invert).unwrap();
 
-        let in_file = File::open(&in_file_path).unwrap();
-        let out_file = File::open(&out_file_path).unwrap();
+        let in_file = File::open(in_file_path).unwrap();
+        let out_file = File::open(out_file_path).unwrap();
 
         let mut in_buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
         let mut out_buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
@@ -510,7 +1200,7 @@ mod bench {
                 assert_eq!(255 - in_buffer[i], out_buffer[i]);
             }
 
-            bytes_count = bytes_count - to_read;
+            bytes_count -= to_read;
         }
     }
 
@@ -522,11 +1212,11 @@ mod bench {
         let in_file_path = get_test_file_path();
         let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/greyscale.test.ppm"));
 
-        let mut img = new_with_file_bin(&in_file_path).unwrap();
-        img.process_and_output(&out_file_path, ImageProcess::Greyscale).unwrap();
+        let mut img = new_with_file_bin(in_file_path).unwrap();
+        img.process_and_output(out_file_path, ImageProcess::Greyscale).unwrap();
 
-        let in_file = File::open(&in_file_path).unwrap();
-        let out_file = File::open(&out_file_path).unwrap();
+        let in_file = File::open(in_file_path).unwrap();
+        let out_file = File::open(out_file_path).unwrap();
 
         let mut in_buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
         let mut out_buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
@@ -560,25 +1250,479 @@ mod bench {
 
                 let grey: u32 = (in_pixel.r + in_pixel.b + in_pixel.g) / 3;
 
-                assert_eq!(grey, out_buffer[(i * 3)] as u32);
+                assert_eq!(grey, out_buffer[i * 3 ] as u32);
                 assert_eq!(grey, out_buffer[(i * 3) + 1] as u32);
                 assert_eq!(grey, out_buffer[(i * 3) + 2] as u32);
             }
 
-            bytes_count = bytes_count - to_read;
+            bytes_count -= to_read;
         }
     }
 
    
 
+    #[test]
+    fn test_invert_16_bits() {
+        let in_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/deep.ppm"));
+        let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/deep.invert.test.ppm"));
+
+        let mut img = new_with_file_bin(in_file_path).unwrap();
+        assert_eq!(2, img.bytes_per_sample());
+        img.process_and_output(out_file_path, ImageProcess::Invert).unwrap();
+
+        let original = img.load().unwrap();
+        let inverted = new_with_file_bin(out_file_path).unwrap().load().unwrap();
+
+        assert_eq!(65535, inverted.max_value());
+        for (in_pixel, out_pixel) in original.pixels().iter().zip(inverted.pixels()) {
+            assert_eq!(65535 - in_pixel.red, out_pixel.red);
+            assert_eq!(65535 - in_pixel.green, out_pixel.green);
+            assert_eq!(65535 - in_pixel.blue, out_pixel.blue);
+        }
+    }
+
+    #[test]
+    fn test_invert_maxval_15() {
+        let in_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/legacy.ppm"));
+        let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/legacy.invert.test.ppm"));
+
+        let mut img = new_with_file_bin(in_file_path).unwrap();
+        assert_eq!(15, img.rgb_max_value);
+        img.process_and_output(out_file_path, ImageProcess::Invert).unwrap();
+
+        let original = img.load().unwrap();
+        let inverted = new_with_file_bin(out_file_path).unwrap().load().unwrap();
+
+        assert_eq!(15, inverted.max_value());
+        for (in_pixel, out_pixel) in original.pixels().iter().zip(inverted.pixels()) {
+            assert_eq!(15 - in_pixel.red, out_pixel.red);
+            assert_eq!(15 - in_pixel.green, out_pixel.green);
+            assert_eq!(15 - in_pixel.blue, out_pixel.blue);
+        }
+    }
+
+    #[test]
+    fn test_truncated() {
+        let in_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/truncated.ppm"));
+
+        let mut img = new_with_file_bin(in_file_path).unwrap();
+
+        match img.load() {
+            Err(PpmError::TruncatedPixelData { expected, got }) => assert_eq!((12, 5), (expected, got)),
+            _ => panic!("expected a truncated pixel data error"),
+        }
+    }
+
+    #[test]
+    fn test_in_memory() {
+        let input = b"P6\n2 1\n255\n\x00\x10\xff\x80\x80\x80".to_vec();
+
+        let mut img = new_with_reader_bin(std::io::Cursor::new(input)).unwrap();
+        assert_eq!(11, img.pixels_offset);
+
+        let mut output: Vec<u8> = vec![];
+        img.process_and_write(&mut output, ImageProcess::Invert).unwrap();
+
+        assert_eq!(b"P6\n2 1\n255\n\xff\xef\x00\x7f\x7f\x7f".to_vec(), output);
+    }
+
+    #[test]
+    fn test_stream() {
+        let input = std::fs::read(get_test_file_path()).unwrap();
+
+        let mut expected: Vec<u8> = vec![];
+        new_with_reader_bin(std::io::Cursor::new(&input)).unwrap().process_and_write(&mut expected, ImageProcess::Invert).unwrap();
+
+        // A chain of slices through a small buffer can't seek, just like a socket.
+        let (first, second) = input.split_at(1000);
+        let img = new_with_stream_bin(BufReader::with_capacity(64, first.chain(second))).unwrap();
+        assert_eq!((512, 512, 15), (img.width, img.height, img.pixels_offset));
+
+        let mut output: Vec<u8> = vec![];
+        img.process_stream(&Processor::new(3), &mut output, ImageProcess::Invert).unwrap();
+        assert_eq!(expected, output);
+
+        match new_with_stream_bin(&b"P6 2 1 255\n\x01\x02\x03"[..]).unwrap().process_stream(&Processor::new(1), &mut vec![], ImageProcess::Invert) {
+            Err(PpmError::TruncatedPixelData { expected, got }) => assert_eq!((6, 3), (expected, got)),
+            _ => panic!("expected a truncated pixel data error"),
+        }
+    }
+
+    #[test]
+    fn test_concurrent_processing() {
+        let expected = new_with_file_bin(get_test_file_path()).unwrap().load().unwrap();
+
+        // Each thread streams the same image at once, into its own output.
+        let outputs: Vec<Vec<u8>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut img = new_with_file_bin(get_test_file_path()).unwrap();
+                        let mut output: Vec<u8> = vec![];
+                        img.process_and_write(&mut output, ImageProcess::Invert).unwrap();
+                        output
+                    })
+                })
+                .collect();
+
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+
+        for output in outputs {
+            let inverted = new_with_reader_bin(std::io::Cursor::new(output)).unwrap().load().unwrap();
+            for (in_pixel, out_pixel) in expected.pixels().iter().zip(inverted.pixels()) {
+                assert_eq!(255 - in_pixel.red, out_pixel.red);
+                assert_eq!(255 - in_pixel.green, out_pixel.green);
+                assert_eq!(255 - in_pixel.blue, out_pixel.blue);
+            }
+        }
+    }
+
+    #[test]
+    fn test_shared_processor() {
+        let single = Processor::new(1);
+        let pool = Processor::new(4);
+
+        // Both processors are reused for every image, and must agree on every byte.
+        for in_file_path in [get_test_file_path(), Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/deep.ppm"))] {
+            let mut outputs: Vec<Vec<u8>> = vec![];
+
+            for processor in [&single, &pool] {
+                let mut output: Vec<u8> = vec![];
+                new_with_file_bin(in_file_path).unwrap().process_with(processor, &mut output, ImageProcess::Greyscale).unwrap();
+                outputs.push(output);
+            }
+
+            assert_eq!(outputs[0], outputs[1]);
+        }
+    }
+
+    #[test]
+    fn test_pipeline_keeps_order() {
+        // Many workers means many small chunks, all of them in flight at once.
+        let mut pool_output: Vec<u8> = vec![];
+        new_with_file_bin(get_test_file_path()).unwrap().process_with(&Processor::new(64), &mut pool_output, ImageProcess::Invert).unwrap();
+
+        let mut single_output: Vec<u8> = vec![];
+        new_with_file_bin(get_test_file_path()).unwrap().process_with(&Processor::new(1), &mut single_output, ImageProcess::Invert).unwrap();
+
+        assert_eq!(single_output, pool_output);
+    }
+
+    // A writer which fails once it got enough bytes.
+    struct FailingWriter(usize);
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if buf.len() > self.0 {
+                return Err(std::io::Error::other("disk full"));
+            }
+            self.0 -= buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pipeline_write_error() {
+        let mut img = new_with_file_bin(get_test_file_path()).unwrap();
+
+        match img.process_with(&Processor::new(64), &mut FailingWriter(100_000), ImageProcess::Invert) {
+            Err(PpmError::Io(e)) => assert_eq!("disk full", e.to_string()),
+            _ => panic!("expected an I/O error"),
+        }
+    }
+
+    #[test]
+    fn test_pipeline_single_pass() {
+        let pipeline = Pipeline::new()
+            .then(ImageProcess::Greyscale)
+            .then(ImageProcess::Brightness(20))
+            .then(ImageProcess::Invert);
+
+        let mut output: Vec<u8> = vec![];
+        new_with_file_bin(get_test_file_path()).unwrap().process_and_write(&mut output, pipeline.clone()).unwrap();
+
+        // Same result as running each process after the other, through intermediate images.
+        let mut expected: Vec<u8> = vec![];
+        new_with_file_bin(get_test_file_path()).unwrap().process_and_write(&mut expected, ImageProcess::Greyscale).unwrap();
+        for process in [ImageProcess::Brightness(20), ImageProcess::Invert] {
+            let mut next: Vec<u8> = vec![];
+            new_with_reader_bin(std::io::Cursor::new(expected)).unwrap().process_and_write(&mut next, process).unwrap();
+            expected = next;
+        }
+
+        assert_eq!(expected, output);
+    }
+
+    #[test]
+    fn test_brightness_clamps() {
+        let input = b"P6\n2 1\n255\n\x00\x10\xf0\x80\x80\x80".to_vec();
+
+        let mut output: Vec<u8> = vec![];
+        new_with_reader_bin(std::io::Cursor::new(input.clone())).unwrap().process_and_write(&mut output, ImageProcess::Brightness(32)).unwrap();
+        assert_eq!(b"\x20\x30\xff\xa0\xa0\xa0".to_vec(), output[11..].to_vec());
+
+        output.clear();
+        new_with_reader_bin(std::io::Cursor::new(input)).unwrap().process_and_write(&mut output, ImageProcess::Brightness(-32)).unwrap();
+        assert_eq!(b"\x00\x00\xd0\x60\x60\x60".to_vec(), output[11..].to_vec());
+    }
+
+    // A user-defined operation, swapping the red and blue channels.
+    struct SwapRedBlue;
+
+    impl PixelOp for SwapRedBlue {
+        fn apply(&self, pixel: &mut BinaryPixel, _rgb_max_value: u32) {
+            std::mem::swap(&mut pixel.r, &mut pixel.b);
+        }
+    }
+
+    #[test]
+    fn test_custom_operations() {
+        let input = b"P6\n2 1\n255\n\x01\x02\x03\x80\x90\xa0".to_vec();
+
+        // Closures capture their own settings.
+        let threshold = 0x85;
+        let pipeline = Pipeline::new()
+            .then(SwapRedBlue)
+            .then(move |pixel: &mut BinaryPixel, max: u32| {
+                pixel.g = if pixel.g > threshold { max } else { 0 };
+            });
+        assert_eq!(2, pipeline.len());
+
+        let mut output: Vec<u8> = vec![];
+        new_with_reader_bin(std::io::Cursor::new(input)).unwrap().process_with(&Processor::new(2), &mut output, pipeline).unwrap();
+
+        assert_eq!(b"\x03\x00\x01\xa0\xff\x80".to_vec(), output[11..].to_vec());
+    }
+
+    #[test]
+    fn test_panicking_operation() {
+        let panicking = |pixel: &mut BinaryPixel, _max: u32| {
+            if pixel.r == 0x80 {
+                panic!("unexpected pixel");
+            }
+        };
+
+        // The caller gets an error, whether the operation runs on workers or on its own thread.
+        for threads in [1, 4] {
+            let input = b"P6\n2 1\n255\n\x01\x02\x03\x80\x90\xa0".to_vec();
+            let mut output: Vec<u8> = vec![];
+
+            match new_with_reader_bin(std::io::Cursor::new(input)).unwrap().process_with(&Processor::new(threads), &mut output, panicking) {
+                Err(PpmError::ProcessingFailed) => (),
+                _ => panic!("expected a failed processing"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_rows() {
+        let mut img = new_with_file_bin(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/deep.ppm"))).unwrap();
+        let image = img.load().unwrap();
+
+        let rows: Vec<Row> = img.rows().unwrap().map(|row| row.unwrap()).collect();
+
+        assert_eq!(vec![0, 1], rows.iter().map(|row| row.y).collect::<Vec<usize>>());
+        assert_eq!(image.pixels()[..4], rows[0].pixels[..]);
+        assert_eq!(image.pixels()[4..], rows[1].pixels[..]);
+
+        let mut truncated = new_with_file_bin(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/truncated.ppm"))).unwrap();
+        assert!(truncated.rows().unwrap().any(|row| row.is_err()));
+    }
+
+    #[test]
+    fn test_process_rows_with_coordinates() {
+        let input = b"P6\n2 3\n255\n\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        let gradient = Positioned(|pixel: &mut BinaryPixel, x: usize, y: usize, _max: u32| {
+            pixel.r = x as u32;
+            pixel.g = y as u32;
+        });
+
+        // Only the last two rows get the gradient.
+        let mut output: Vec<u8> = vec![];
+        new_with_reader_bin(std::io::Cursor::new(input)).unwrap().process_rows_with(&Processor::new(4), &mut output, 1..3, gradient).unwrap();
+
+        assert_eq!(b"\x00\x00\x00\x00\x00\x00\x00\x01\x00\x01\x01\x00\x00\x02\x00\x01\x02\x00".to_vec(), output[11..].to_vec());
+    }
+
+    #[test]
+    fn test_read_region() {
+        let mut img = new_with_file_bin(get_test_file_path()).unwrap();
+        let image = img.load().unwrap();
+
+        assert_eq!(image.pixels()[512 * 200 + 100], img.get_pixel(100, 200).unwrap());
+
+        let region = img.read_region(500, 10, 12, 3).unwrap();
+        assert_eq!((12, 3, 255), (region.width(), region.height(), region.max_value()));
+        for row in 0..3 {
+            let start = 512 * (10 + row) + 500;
+            assert_eq!(image.pixels()[start..start + 12], region.pixels()[row * 12..(row + 1) * 12]);
+        }
+
+        match img.read_region(500, 10, 13, 1) {
+            Err(PpmError::RegionOutOfBounds { x, y, width, height }) => assert_eq!((500, 10, 13, 1), (x, y, width, height)),
+            _ => panic!("expected a region out of bounds error"),
+        }
+        assert!(img.get_pixel(0, 512).is_err());
+
+        // Regions ending past usize::MAX don't wrap around.
+        match img.read_region(usize::MAX, 0, 2, 1) {
+            Err(PpmError::RegionOutOfBounds { x, .. }) => assert_eq!(usize::MAX, x),
+            _ => panic!("expected a region out of bounds error"),
+        }
+        assert!(img.read_region(0, 1, 1, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_write_region() {
+        let file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/deep.region.test.ppm"));
+        std::fs::copy(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/deep.ppm")), file_path).unwrap();
+
+        let mut img = new_with_file_bin(file_path).unwrap();
+        let original = img.load().unwrap();
+
+        // An 8 bits region, rescaled to the image's 16 bits.
+        let mut region = Image::new(2, 1, Format::Binary);
+        region.pixels_mut()[0] = Pixel { red: 255, green: 0, blue: 1 };
+        assert_eq!(255, region.max_value());
+
+        let mut writer = std::fs::OpenOptions::new().write(true).open(file_path).unwrap();
+        img.write_region(&mut writer, 1, 1, &region).unwrap();
+
+        let image = img.load().unwrap();
+        assert_eq!(Pixel { red: 65535, green: 0, blue: 257 }, image.pixels()[5]);
+        assert_eq!(Pixel { red: 0, green: 0, blue: 0 }, image.pixels()[6]);
+        assert_eq!(original.pixels()[..5], image.pixels()[..5]);
+        assert_eq!(original.pixels()[7], image.pixels()[7]);
+
+        assert!(img.write_region(&mut writer, 3, 0, &region).is_err());
+    }
+
+    #[test]
+    fn test_progress() {
+        let reports = Arc::new(std::sync::Mutex::new(vec![]));
+        let monitor = {
+            let reports = Arc::clone(&reports);
+            Monitor::new().on_progress(move |progress| reports.lock().unwrap().push(progress))
+        };
+
+        let mut output: Vec<u8> = vec![];
+        new_with_file_bin(get_test_file_path())
+            .unwrap()
+            .process_monitored(&Processor::new(64), &mut output, ImageProcess::Invert, &monitor)
+            .unwrap();
+
+        // Many small chunks, written in order.
+        let reports = reports.lock().unwrap();
+        assert!(reports.len() > 1);
+        assert!(reports.windows(2).all(|pair| pair[0].done < pair[1].done));
+        assert_eq!(Progress { done: 512 * 512 * 3, total: 512 * 512 * 3 }, *reports.last().unwrap());
+    }
+
+    #[test]
+    fn test_cancelled() {
+        let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/alaska.cancelled.test.ppm"));
+
+        let token = crate::progress::CancellationToken::new();
+        token.cancel();
+
+        let mut img = new_with_file_bin(get_test_file_path()).unwrap();
+        match img.process_and_output_monitored(out_file_path, ImageProcess::Invert, &Monitor::new().with_cancellation(token)) {
+            Err(PpmError::Cancelled) => assert!(!out_file_path.exists()),
+            _ => panic!("expected a cancelled process"),
+        }
+    }
+
+    #[test]
+    fn test_cancelled_mid_run() {
+        let in_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/large.test.ppm"));
+        let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/large.cancelled.output.test.ppm"));
+
+        // Larger than the chunks in flight at once, so reading waits for the writer and sees the cancellation.
+        let mut input = b"P6\n2048 2048\n255\n".to_vec();
+        input.extend((0..2048 * 2048 * 3).map(|i| (i % 251) as u8));
+        std::fs::write(in_file_path, input).unwrap();
+
+        // Cancelled once the first chunk is written.
+        let token = crate::progress::CancellationToken::new();
+        let monitor = {
+            let token = token.clone();
+            Monitor::new().on_progress(move |_| token.cancel())
+        }
+        .with_cancellation(token);
+
+        let mut img = new_with_file_bin(in_file_path).unwrap();
+        match img.process_and_output_monitored(out_file_path, ImageProcess::Invert, &monitor) {
+            Err(PpmError::Cancelled) => assert!(!out_file_path.exists()),
+            _ => panic!("expected a cancelled process"),
+        }
+
+        // Same for a filter.
+        match img.filter_and_output_monitored(out_file_path, crate::neighborhood::Convolution::sharpen(), &monitor) {
+            Err(PpmError::Cancelled) => assert!(!out_file_path.exists()),
+            _ => panic!("expected a cancelled filter"),
+        }
+    }
+
+    #[test]
+    fn test_failed_output_removed() {
+        let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/truncated.output.test.ppm"));
+
+        let mut img = new_with_file_bin(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/truncated.ppm"))).unwrap();
+        match img.process_and_output(out_file_path, ImageProcess::Invert) {
+            Err(PpmError::TruncatedPixelData { .. }) => assert!(!out_file_path.exists()),
+            _ => panic!("expected a truncated pixel data error"),
+        }
+    }
+
+    #[test]
+    fn test_header_layout() {
+        // Headers on a single line or split by comments, the pixels section starts right after the max value's whitespace.
+        for input in [&b"P6 1 1 255\n\x0a\x20\x23"[..], &b"P6\n# size\n1\t1 # one pixel\n255 \x0a\x20\x23"[..]] {
+            let mut img = new_with_reader_bin(std::io::Cursor::new(input.to_vec())).unwrap();
+
+            assert_eq!((1, 1, 255), (img.width, img.height, img.rgb_max_value));
+            assert_eq!(Pixel { red: 0x0a, green: 0x20, blue: 0x23 }, img.get_pixel(0, 0).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_comment_after_last_header() {
+        // A comment right after the max value is whitespace too, the pixels section starts after its line ending.
+        let mut img = new_with_reader_bin(std::io::Cursor::new(b"P6 1 1 255# done\n\x01\x02\x03".to_vec())).unwrap();
+        assert_eq!(Pixel { red: 1, green: 2, blue: 3 }, img.get_pixel(0, 0).unwrap());
+        assert_eq!("done", img.comments[0].text);
+        assert_eq!(Some((1, 11)), img.comments[0].position);
+
+        // Without a comment, a '#' after the whitespace is a sample.
+        let mut img = new_with_reader_bin(std::io::Cursor::new(b"P6 1 1 255\n#\x02\x03".to_vec())).unwrap();
+        assert_eq!(Pixel { red: b'#' as u16, green: 2, blue: 3 }, img.get_pixel(0, 0).unwrap());
+        assert!(img.comments.is_empty());
+
+        let image = crate::image::decode(&b"P5 2 1 255#c\r\x04\x05"[..]).unwrap();
+        assert_eq!(vec![4, 5], image.pixels().iter().map(|pixel| pixel.red).collect::<Vec<u16>>());
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        match new_with_file_bin(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p3/test/test.ppm"))) {
+            Err(PpmError::BadMagic(magic_number)) => assert_eq!("P3", magic_number),
+            _ => panic!("expected a bad magic number error"),
+        }
+    }
+
     #[bench]
     fn bench_greyscale_image(b: &mut Bencher) {
         let in_file_path = get_test_file_path();
         let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/greyscale.bench.ppm"));
 
-        let mut img = new_with_file_bin(&in_file_path).unwrap();
+        let mut img = new_with_file_bin(in_file_path).unwrap();
         
-        b.iter(|| img.process_and_output(&out_file_path, ImageProcess::Greyscale).unwrap());
+        b.iter(|| img.process_and_output(out_file_path, ImageProcess::Greyscale).unwrap());
     }
 
     #[bench]
@@ -586,8 +1730,19 @@ mod bench {
         let in_file_path = get_test_file_path();
         let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/invert.bench.ppm"));
 
-        let mut img = new_with_file_bin(&in_file_path).unwrap();
+        let mut img = new_with_file_bin(in_file_path).unwrap();
+
+        b.iter(|| img.process_and_output(out_file_path, ImageProcess::Invert).unwrap());
+    }
+
+    // Same as bench_invert_image(), through memory-mapped input and output files.
+    #[bench]
+    fn bench_invert_image_mmap(b: &mut Bencher) {
+        let in_file_path = get_test_file_path();
+        let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/invert.mmap.bench.ppm"));
+
+        let img = crate::mmap::new_with_file_mmap(in_file_path).unwrap();
 
-        b.iter(|| img.process_and_output(&out_file_path, ImageProcess::Invert).unwrap());
+        b.iter(|| img.process_and_output(out_file_path, ImageProcess::Invert).unwrap());
     }
 }
@@ -0,0 +1,557 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, SeekFrom};
+use std::path::Path;
+
+use crate::error::PpmError;
+use crate::header::{check_dimensions, Comment, Tokenizer};
+use crate::image::{Image, Kind};
+use crate::p6::{bytes_per_sample, output_file, process_pixels, Layout, Pipeline};
+use crate::processor::Processor;
+use crate::progress::Monitor;
+
+// The PAM magic number, PAM files have no ASCII variant.
+const MAGIC_NUMBER: &str = "P7";
+
+// What a PAM tuple stands for, as written in the TUPLTYPE header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TupleType {
+    BlackAndWhite,
+    Grayscale,
+    Rgb,
+    BlackAndWhiteAlpha,
+    GrayscaleAlpha,
+    RgbAlpha,
+    Other(String),
+}
+
+impl TupleType {
+
+    pub fn from_name(name: &str) -> TupleType {
+        match name {
+            "BLACKANDWHITE" => TupleType::BlackAndWhite,
+            "GRAYSCALE" => TupleType::Grayscale,
+            "RGB" => TupleType::Rgb,
+            "BLACKANDWHITE_ALPHA" => TupleType::BlackAndWhiteAlpha,
+            "GRAYSCALE_ALPHA" => TupleType::GrayscaleAlpha,
+            "RGB_ALPHA" => TupleType::RgbAlpha,
+            _ => TupleType::Other(name.to_string()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            TupleType::BlackAndWhite => "BLACKANDWHITE",
+            TupleType::Grayscale => "GRAYSCALE",
+            TupleType::Rgb => "RGB",
+            TupleType::BlackAndWhiteAlpha => "BLACKANDWHITE_ALPHA",
+            TupleType::GrayscaleAlpha => "GRAYSCALE_ALPHA",
+            TupleType::RgbAlpha => "RGB_ALPHA",
+            TupleType::Other(name) => name,
+        }
+    }
+
+    // How many color samples a tuple starts with, and its expected depth (with the alpha sample).
+    // We don't know how to process other tuple types.
+    fn samples(&self) -> Option<(usize, usize)> {
+        match self {
+            TupleType::BlackAndWhite | TupleType::Grayscale => Some((1, 1)),
+            TupleType::Rgb => Some((3, 3)),
+            TupleType::BlackAndWhiteAlpha | TupleType::GrayscaleAlpha => Some((1, 2)),
+            TupleType::RgbAlpha => Some((3, 4)),
+            TupleType::Other(_) => None,
+        }
+    }
+}
+
+/* 
+    Our PamImage structure, the PAM (P7) counterpart of p6::BinaryImage.
+    Only the headers are stored, pixels are streamed from the reader when processing.
+    Processes only transform the color samples, the alpha channel is copied untouched.
+    Just like BinaryImage, images read from non-seekable readers (see new_with_stream_pam()) are processed once, with process_stream().
+*/
+pub struct PamImage<R: BufRead = BufReader<File>> {
+    pub reader: R,
+    pub pixels_offset: usize,
+    pub height: usize,
+    pub width: usize,
+    pub depth: usize,
+    pub max_value: usize,
+    pub tuple_type: TupleType,
+    // Comments of the headers, written back by the processing functions. Their after field counts header lines, P7 included.
+    pub comments: Vec<Comment>,
+}
+
+impl<R: BufRead> PamImage<R> {
+
+    // Same as process_with(), straight from the reader's current position without seeking. The image is consumed, as its pixels can only be read once.
+    pub fn process_stream<W: Write + Send, P: Into<Pipeline>>(self, processor: &Processor, writer: &mut W, process: P) -> Result<(), PpmError> {
+        self.process_stream_monitored(processor, writer, process, &Monitor::new())
+    }
+
+    // Same as process_stream(), but progress is reported to the monitor and the process can be cancelled through it.
+    pub fn process_stream_monitored<W: Write + Send, P: Into<Pipeline>>(
+        mut self,
+        processor: &Processor,
+        writer: &mut W,
+        process: P,
+        monitor: &Monitor,
+    ) -> Result<(), PpmError> {
+        self.write_processed(processor, writer, process, monitor)
+    }
+
+    // Write the headers and the processed pixels, read from the reader's current position.
+    fn write_processed<W: Write + Send, P: Into<Pipeline>>(
+        &mut self,
+        processor: &Processor,
+        writer: &mut W,
+        process: P,
+        monitor: &Monitor,
+    ) -> Result<(), PpmError> {
+
+        let layout = self.layout()?;
+
+        write_headers(writer, self.width, self.height, self.depth, self.max_value, &self.tuple_type, &self.comments)?;
+
+        process_pixels(
+            processor,
+            monitor,
+            &mut self.reader,
+            writer,
+            (self.width, self.height),
+            0..self.height,
+            layout,
+            self.max_value as u32,
+            process.into(),
+        )
+    }
+
+    // How many bytes a single sample takes in the pixels section.
+    pub fn bytes_per_sample(&self) -> usize {
+        bytes_per_sample(self.max_value)
+    }
+
+    fn layout(&self) -> Result<Layout, PpmError> {
+        match self.tuple_type.samples() {
+            Some((color_samples, _depth)) => Ok(Layout {
+                sample_bytes: self.bytes_per_sample(),
+                depth: self.depth,
+                color_samples,
+            }),
+            None => Err(PpmError::BadHeader(format!("Can't process {} tuple type.", self.tuple_type.name()))),
+        }
+    }
+}
+
+impl<R: BufRead + Seek> PamImage<R> {
+
+    /*
+        process_and_output(filename, process)
+
+        Process (transform) image and output it to a file, just like BinaryImage::process_and_output().
+
+        The first parameter is the output's filename.
+        The second parameter is one of ImageProcess enum's values, or a whole Pipeline of them, it tells which transformation that we want to apply.
+
+        Return a Result with nothing if everything went smooth, or an Err if we don't know how to process the tuple type.
+    */
+    pub fn process_and_output<P: Into<Pipeline>>(&mut self, filename: &Path, process: P) -> Result<(), PpmError> {
+        self.process_and_output_monitored(filename, process, &Monitor::new())
+    }
+
+    // Same as process_and_output(), but progress is reported to the monitor and the process can be cancelled through it. No partial output file is left behind.
+    pub fn process_and_output_monitored<P: Into<Pipeline>>(&mut self, filename: &Path, process: P, monitor: &Monitor) -> Result<(), PpmError> {
+        output_file(filename, |writer| self.process_monitored(&Processor::default(), writer, process, monitor))
+    }
+
+    // Same as process_and_output(), but the processed image goes to any writer, which is not flushed.
+    pub fn process_and_write<W: Write + Send, P: Into<Pipeline>>(&mut self, writer: &mut W, process: P) -> Result<(), PpmError> {
+        self.process_with(&Processor::default(), writer, process)
+    }
+
+    // Same as process_and_write(), on an existing Processor's workers.
+    pub fn process_with<W: Write + Send, P: Into<Pipeline>>(
+        &mut self,
+        processor: &Processor,
+        writer: &mut W,
+        process: P,
+    ) -> Result<(), PpmError> {
+        self.process_monitored(processor, writer, process, &Monitor::new())
+    }
+
+    // Same as process_with(), but progress is reported to the monitor and the process can be cancelled through it, just like BinaryImage::process_monitored().
+    pub fn process_monitored<W: Write + Send, P: Into<Pipeline>>(
+        &mut self,
+        processor: &Processor,
+        writer: &mut W,
+        process: P,
+        monitor: &Monitor,
+    ) -> Result<(), PpmError> {
+
+        // Check the tuple type before seeking anything.
+        self.layout()?;
+
+        // Seek pixels section starting position.
+        self.reader.seek(SeekFrom::Start(self.pixels_offset as u64))?;
+
+        self.write_processed(processor, writer, process, monitor)
+    }
+}
+
+/*
+    new_with_file_pam(filename)
+
+    Create a new PamImage structure for an input file, this struct could then be used with process_and_output(out_filename, process).
+
+    PAM headers are one per line ("WIDTH 512"), they end with an ENDHDR line after which the pixels section starts.
+
+    Will return Result with Err if a header is missing or invalid.
+*/
+pub fn new_with_file_pam(filename: &Path) -> Result<PamImage, PpmError> {
+    new_with_reader_pam(BufReader::new(File::open(filename)?))
+}
+
+// Same as new_with_file_pam(), for any seekable reader. The pixels offset is relative to the reader's start.
+pub fn new_with_reader_pam<R: BufRead + Seek>(mut reader: R) -> Result<PamImage<R>, PpmError> {
+
+    let start = reader.stream_position()? as usize;
+
+    let mut image = new_with_stream_pam(reader)?;
+    image.pixels_offset += start;
+
+    Ok(image)
+}
+
+/*
+    new_with_stream_pam(reader)
+
+    Same as new_with_reader_pam(), for readers which can't seek: stdin, a socket or an HTTP body.
+    The reader is left at the pixels section, ready for process_stream(). The pixels offset is relative to its position when it was given.
+*/
+pub fn new_with_stream_pam<R: BufRead>(mut reader: R) -> Result<PamImage<R>, PpmError> {
+
+    let mut tokens = Tokenizer::new(&mut reader);
+
+    // Magic number comes first, comments are kept with the number of header lines before them.
+    tokens.keep_comments(0);
+    let magic_number = next_word(&mut tokens)?.map(|(word, _)| word).unwrap_or_default();
+    if magic_number != MAGIC_NUMBER {
+        return Err(PpmError::BadMagic(magic_number));
+    }
+
+    let mut width = None;
+    let mut height = None;
+    let mut depth = None;
+    let mut max_value = None;
+    let mut tuple_type: Option<String> = None;
+
+    let mut header_lines = 1;
+    tokens.keep_comments(header_lines);
+    let mut next = next_word(&mut tokens)?;
+
+    loop {
+        let (keyword, line) = match next {
+            Some(word) => word,
+            None => return Err(PpmError::BadHeader("Missing ENDHDR header.".to_string())),
+        };
+        if keyword == "ENDHDR" {
+            break;
+        }
+
+        // Every other header comes with a value.
+        let value = match next_word(&mut tokens)? {
+            Some((value, _)) => value,
+            None => return Err(PpmError::BadHeader(format!("Missing {} value at line {}.", keyword, line))),
+        };
+
+        header_lines += 1;
+        tokens.keep_comments(header_lines);
+        next = next_word(&mut tokens)?;
+
+        match keyword.as_str() {
+            "WIDTH" => width = Some(parse_number(value, line, PpmError::BadDimension)?),
+            "HEIGHT" => height = Some(parse_number(value, line, PpmError::BadDimension)?),
+            "DEPTH" => depth = Some(parse_number(value, line, PpmError::BadHeader)?),
+            "MAXVAL" => max_value = Some(parse_number(value, line, PpmError::BadHeader)?),
+
+            // The tuple type is the rest of its line, several TUPLTYPE lines are joined with a space.
+            "TUPLTYPE" => {
+                let mut words = vec![value];
+                while let Some((word, _)) = next.take_if(|(_, next_line)| *next_line == line) {
+                    words.push(word);
+                    next = next_word(&mut tokens)?;
+                }
+
+                let value = words.join(" ");
+                tuple_type = Some(match tuple_type {
+                    Some(previous) => format!("{} {}", previous, value),
+                    None => value,
+                })
+            }
+            _ => return Err(PpmError::BadHeader(format!("Unknown {} header.", keyword))),
+        }
+    }
+
+    // The pixels section starts right after the ENDHDR line ending.
+    tokens.skip_comment_line()?;
+    let pixels_offset = tokens.offset();
+    let comments = tokens.take_comments();
+
+    let missing = |name: &str| PpmError::BadHeader(format!("Missing {} header.", name));
+
+    let image = PamImage {
+        pixels_offset,
+        reader,
+        width: width.ok_or_else(|| missing("WIDTH"))?,
+        height: height.ok_or_else(|| missing("HEIGHT"))?,
+        depth: depth.ok_or_else(|| missing("DEPTH"))?,
+        max_value: max_value.ok_or_else(|| missing("MAXVAL"))?,
+        tuple_type: TupleType::from_name(&tuple_type.unwrap_or_default()),
+        comments,
+    };
+
+    if !(1..=65535).contains(&image.max_value) {
+        return Err(PpmError::UnsupportedMaxval(image.max_value));
+    }
+
+    check_dimensions(image.width, image.height, image.depth)?;
+
+    // Known tuple types must come with the right depth.
+    if let Some((_color_samples, expected_depth)) = image.tuple_type.samples() {
+        if image.depth != expected_depth {
+            let msg = format!("{} tuple type needs a depth of {}, got {}.", image.tuple_type.name(), expected_depth, image.depth);
+            return Err(PpmError::BadHeader(msg));
+        }
+    }
+
+    Ok(image)
+}
+
+// The next header word and the line it's on, None at the end of the file.
+fn next_word<R: BufRead>(tokens: &mut Tokenizer<R>) -> Result<Option<(String, usize)>, PpmError> {
+    Ok(tokens.next_token()?.map(|token| (token.text(), token.line)))
+}
+
+fn parse_number(value: String, line: usize, error: fn(String) -> PpmError) -> Result<usize, PpmError> {
+    match value.parse::<usize>() {
+        Ok(parsed) => Ok(parsed),
+        Err(_e) => Err(error(format!("{} at line {}", value, line))),
+    }
+}
+
+/*
+    Write the PAM headers, the pixels section is expected to follow right after.
+    Each comment goes on its own line, after the header line it followed (its after field, P7 being the first line).
+*/
+pub fn write_headers<W: Write>(
+    writer: &mut W,
+    width: usize,
+    height: usize,
+    depth: usize,
+    max_value: usize,
+    tuple_type: &TupleType,
+    comments: &[Comment],
+) -> Result<(), PpmError> {
+    let mut lines = vec![
+        MAGIC_NUMBER.to_string(),
+        format!("WIDTH {}", width),
+        format!("HEIGHT {}", height),
+        format!("DEPTH {}", depth),
+        format!("MAXVAL {}", max_value),
+    ];
+    if !tuple_type.name().is_empty() {
+        lines.push(format!("TUPLTYPE {}", tuple_type.name()));
+    }
+
+    for (index, line) in lines.iter().enumerate() {
+        writeln!(writer, "{}", line)?;
+
+        // Comments can't come before the magic number nor after ENDHDR.
+        for comment in comments.iter().filter(|comment| comment.after.clamp(1, lines.len()) == index + 1) {
+            for text in comment.text.split(['\n', '\r']) {
+                match text {
+                    "" => writeln!(writer, "#")?,
+                    _ => writeln!(writer, "# {}", text)?,
+                }
+            }
+        }
+    }
+
+    writeln!(writer, "ENDHDR")?;
+    Ok(())
+}
+
+//  Function that writes an in-memory image as PAM, with the tuple type matching its kind and no alpha channel.
+pub fn write_image<W: Write>(image: &Image, writer: &mut W) -> Result<(), PpmError> {
+
+    let (tuple_type, depth) = match image.kind {
+        Kind::Bitmap => (TupleType::BlackAndWhite, 1),
+        Kind::Greymap => (TupleType::Grayscale, 1),
+        Kind::Pixmap => (TupleType::Rgb, 3),
+    };
+
+    write_headers(writer, image.width, image.height, depth, image.max_value as usize, &tuple_type, &image.comments)?;
+
+    let sample_bytes = bytes_per_sample(image.max_value as usize);
+
+    for pixel in image.pixels.iter() {
+        let samples = if depth == 1 {
+            vec![pixel.grey()]
+        } else {
+            vec![pixel.red, pixel.green, pixel.blue]
+        };
+
+        for sample in samples {
+            match sample_bytes {
+                1 => writer.write_all(&[sample as u8])?,
+                _ => writer.write_all(&sample.to_be_bytes())?,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Module for testing
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::p6::ImageProcess;
+
+    fn read_pixels(image: &mut PamImage) -> Vec<u8> {
+        let mut bytes = vec![];
+        image.reader.seek(SeekFrom::Start(image.pixels_offset as u64)).unwrap();
+        image.reader.read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_invert_keeps_alpha() {
+        let in_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p7/test/rgba.pam"));
+        let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p7/test/rgba.invert.test.pam"));
+
+        let mut img = new_with_file_pam(in_file_path).unwrap();
+        assert_eq!((3, 2, 4, 255), (img.width, img.height, img.depth, img.max_value));
+        assert_eq!(TupleType::RgbAlpha, img.tuple_type);
+        img.process_and_output(out_file_path, ImageProcess::Invert).unwrap();
+
+        let in_bytes = read_pixels(&mut img);
+        let out_bytes = read_pixels(&mut new_with_file_pam(out_file_path).unwrap());
+
+        assert_eq!(in_bytes.len(), out_bytes.len());
+        for (in_pixel, out_pixel) in in_bytes.chunks(4).zip(out_bytes.chunks(4)) {
+            assert_eq!(255 - in_pixel[0], out_pixel[0]);
+            assert_eq!(255 - in_pixel[1], out_pixel[1]);
+            assert_eq!(255 - in_pixel[2], out_pixel[2]);
+            assert_eq!(in_pixel[3], out_pixel[3]);
+        }
+    }
+
+    #[test]
+    fn test_stream() {
+        let in_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p7/test/rgba.pam"));
+        let input = std::fs::read(in_file_path).unwrap();
+
+        let mut expected = vec![];
+        new_with_file_pam(in_file_path).unwrap().process_and_write(&mut expected, ImageProcess::Greyscale).unwrap();
+
+        // A slice can't seek, just like stdin.
+        let img = new_with_stream_pam(&input[..]).unwrap();
+        assert_eq!(new_with_file_pam(in_file_path).unwrap().pixels_offset, img.pixels_offset);
+
+        let mut output = vec![];
+        img.process_stream(&Processor::new(2), &mut output, ImageProcess::Greyscale).unwrap();
+        assert_eq!(expected, output);
+    }
+
+    #[test]
+    fn test_progress() {
+        let reports = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let monitor = {
+            let reports = std::sync::Arc::clone(&reports);
+            Monitor::new().on_progress(move |progress| reports.lock().unwrap().push(progress))
+        };
+
+        let in_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p7/test/rgba.pam"));
+        let mut output = vec![];
+        new_with_file_pam(in_file_path).unwrap().process_monitored(&Processor::new(2), &mut output, ImageProcess::Invert, &monitor).unwrap();
+
+        // Alpha samples count too.
+        assert_eq!(crate::progress::Progress { done: 3 * 2 * 4, total: 3 * 2 * 4 }, *reports.lock().unwrap().last().unwrap());
+    }
+
+    #[test]
+    fn test_comments_round_trip() {
+        let input = b"P7\n# scanner A\nWIDTH 1\nHEIGHT 1\n  #exposure 1/60\nDEPTH 3\nMAXVAL 255\nTUPLTYPE RGB\n# last\nENDHDR\n\x01\x02\x03";
+
+        let img = new_with_stream_pam(&input[..]).unwrap();
+        let comments: Vec<_> = img.comments.iter().map(|comment| (comment.text.as_str(), comment.after, comment.position)).collect();
+        assert_eq!(vec![("scanner A", 1, Some((2, 1))), ("exposure 1/60", 3, Some((5, 3))), ("last", 6, Some((9, 1)))], comments);
+
+        let mut output = vec![];
+        img.process_stream(&Processor::new(1), &mut output, ImageProcess::Invert).unwrap();
+        assert_eq!(
+            b"P7\n# scanner A\nWIDTH 1\nHEIGHT 1\n# exposure 1/60\nDEPTH 3\nMAXVAL 255\nTUPLTYPE RGB\n# last\nENDHDR\n\xfe\xfd\xfc".to_vec(),
+            output
+        );
+    }
+
+    #[test]
+    fn test_header_tokens() {
+        // Comments may hold any bytes, and the tuple type is the rest of its line.
+        let input = b"P7\n# caf\xe9\nWIDTH 1\nHEIGHT 1\nDEPTH 1\nMAXVAL 255\nTUPLTYPE FOO BAR\nTUPLTYPE BAZ\nENDHDR\n\x01";
+
+        let img = new_with_stream_pam(&input[..]).unwrap();
+        assert_eq!("caf\u{fffd}", img.comments[0].text);
+        assert_eq!("FOO BAR BAZ", img.tuple_type.name());
+        assert_eq!(input.len() - 1, img.pixels_offset);
+
+        // A comment with no line ending is skipped as it's read, the header is then missing its end.
+        let input = format!("P7\nWIDTH 1\n#{}", "x".repeat(100_000));
+        match new_with_stream_pam(input.as_bytes()) {
+            Err(PpmError::BadHeader(msg)) => assert_eq!("Missing ENDHDR header.", msg),
+            _ => panic!("expected a missing ENDHDR"),
+        }
+
+        match new_with_stream_pam(&b"P7\nWIDTH 1\nSIZE 3\nENDHDR\n"[..]) {
+            Err(PpmError::BadHeader(msg)) => assert_eq!("Unknown SIZE header.", msg),
+            _ => panic!("expected an unknown header"),
+        }
+    }
+
+    #[test]
+    fn test_invert_16_bits_grayscale_alpha() {
+        let in_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p7/test/grey_alpha.pam"));
+        let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p7/test/grey_alpha.invert.test.pam"));
+
+        let mut img = new_with_file_pam(in_file_path).unwrap();
+        assert_eq!(2, img.bytes_per_sample());
+        img.process_and_output(out_file_path, ImageProcess::Invert).unwrap();
+
+        let in_bytes = read_pixels(&mut img);
+        let out_bytes = read_pixels(&mut new_with_file_pam(out_file_path).unwrap());
+
+        for (in_pixel, out_pixel) in in_bytes.chunks(4).zip(out_bytes.chunks(4)) {
+            let in_grey = u16::from_be_bytes([in_pixel[0], in_pixel[1]]);
+            let out_grey = u16::from_be_bytes([out_pixel[0], out_pixel[1]]);
+
+            assert_eq!(65535 - in_grey, out_grey);
+            assert_eq!(in_pixel[2..], out_pixel[2..]);
+        }
+    }
+
+    #[test]
+    fn test_write_image() {
+        let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p7/test/alaska.test.pam"));
+
+        let image = crate::image::open(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/alaska.ppm"))).unwrap();
+        let mut writer = std::io::BufWriter::new(File::create(out_file_path).unwrap());
+        write_image(&image, &mut writer).unwrap();
+        drop(writer);
+
+        let mut img = new_with_file_pam(out_file_path).unwrap();
+        assert_eq!((512, 512, 3, TupleType::Rgb), (img.width, img.height, img.depth, img.tuple_type.clone()));
+        assert_eq!(512 * 512 * 3, read_pixels(&mut img).len());
+    }
+}
@@ -0,0 +1,183 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::error::PpmError;
+use crate::header::{read_header_tokens, write_header, Header, Tokenizer, ASCII_LINE_LENGTH, PREALLOCATED_SAMPLES};
+use crate::image::{Format, Image, Kind, Pixel};
+use crate::p6::read_bytes;
+
+/*
+    new_with_file(filename)
+
+    Load a bitmap (PBM) file into an Image, whatever its encoding (P1 or P4).
+    In a PBM file 1 means black, so pixels are stored the other way around with a max value of 1:
+    0 is black and 1 is white, just like any other image.
+
+    Will return Result with Err if the file is not a bitmap or its pixels section is invalid.
+*/
+pub fn new_with_file(filename: &Path) -> Result<Image, PpmError> {
+
+    new_with_reader(BufReader::new(File::open(filename)?))
+}
+
+// Same as new_with_file(), for any buffered reader: a Cursor over bytes already in memory, stdin or a pipe.
+pub fn new_with_reader<R: BufRead>(mut reader: R) -> Result<Image, PpmError> {
+
+    let mut tokens = Tokenizer::new(&mut reader);
+    let header = read_header_tokens(&mut tokens)?;
+
+    if header.kind != Kind::Bitmap {
+        return Err(PpmError::BadMagic(header.kind.magic_number(header.format).to_string()));
+    }
+
+    let pixels = read_pixels(&mut tokens, &header)?;
+    Ok(Image::with_pixels(&header, pixels))
+}
+
+// Read a bitmap pixels section, right after the headers read by the same tokenizer.
+pub(crate) fn read_pixels<R: BufRead>(tokens: &mut Tokenizer<R>, header: &Header) -> Result<Vec<Pixel>, PpmError> {
+
+    let bits = match header.format {
+        Format::Ascii => read_ascii_bits(tokens, header.width * header.height)?,
+        Format::Binary => read_binary_bits(tokens, header.width, header.height)?,
+    };
+
+    Ok(bits
+        .into_iter()
+        .map(|bit| {
+            let sample = 1 - bit as u16;
+            Pixel {
+                red: sample,
+                green: sample,
+                blue: sample,
+            }
+        })
+        .collect())
+}
+
+/*
+    Read count ASCII bits, right after the headers read by the same tokenizer.
+    Whitespace between bits is optional, so every character is a bit of its own.
+*/
+fn read_ascii_bits<R: BufRead>(tokens: &mut Tokenizer<R>, count: usize) -> Result<Vec<u8>, PpmError> {
+
+    let mut bits = Vec::with_capacity(count.min(PREALLOCATED_SAMPLES));
+
+    // Stop right after the last bit, another image may follow.
+    while bits.len() < count {
+        match tokens.next_char()? {
+            Some((b'0', _, _)) => bits.push(0),
+            Some((b'1', _, _)) => bits.push(1),
+            Some((_, line, column)) => return Err(PpmError::InvalidSample { line, column }),
+            None => break,
+        }
+    }
+
+    if bits.len() < count {
+        return Err(PpmError::TruncatedPixelData { expected: count, got: bits.len() });
+    }
+
+    Ok(bits)
+}
+
+// Read packed bits, 8 pixels per byte with the most significant bit first. Each row is padded to a whole byte.
+fn read_binary_bits<R: Read>(reader: &mut R, width: usize, height: usize) -> Result<Vec<u8>, PpmError> {
+
+    let row_bytes = width.div_ceil(8);
+
+    let bytes = read_bytes(reader, row_bytes * height)?;
+    let filled = bytes.len();
+
+    if filled < row_bytes * height {
+        let got = (filled / row_bytes) * width + (filled % row_bytes) * 8;
+        return Err(PpmError::TruncatedPixelData { expected: width * height, got });
+    }
+
+    let mut bits = Vec::with_capacity(width * height);
+
+    for row in bytes.chunks_exact(row_bytes.max(1)).take(height) {
+        for x in 0..width {
+            bits.push((row[x / 8] >> (7 - (x % 8))) & 1);
+        }
+    }
+
+    Ok(bits)
+}
+
+//  Function that writes an image as a bitmap (P1 or P4), pixels darker than half the max value are black.
+pub fn write_image<W: Write>(image: &Image, format: Format, writer: &mut W) -> Result<(), PpmError> {
+    write_header(writer, Kind::Bitmap, format, (image.width, image.height), 1, &image.comments)?;
+
+    if image.width == 0 {
+        return Ok(());
+    }
+
+    for row in image.pixels.chunks(image.width) {
+        let bits: Vec<u8> = row
+            .iter()
+            .map(|pixel| (pixel.grey() as u32 * 2 <= image.max_value as u32) as u8)
+            .collect();
+
+        match format {
+            Format::Ascii => {
+                for line in bits.chunks(ASCII_LINE_LENGTH) {
+                    let line: String = line.iter().map(|bit| (b'0' + bit) as char).collect();
+                    writeln!(writer, "{}", line)?;
+                }
+            }
+            Format::Binary => {
+                let mut bytes = vec![0; image.width.div_ceil(8)];
+                for (x, bit) in bits.iter().enumerate() {
+                    bytes[x / 8] |= bit << (7 - (x % 8));
+                }
+                writer.write_all(&bytes)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Module for testing
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::image::open;
+
+    fn get_test_file_path() -> &'static Path {
+        Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/pbm/test/bitmap.pbm"))
+    }
+
+    #[test]
+    fn test_ascii_and_binary_match() {
+        let ascii = new_with_file(get_test_file_path()).unwrap();
+        let binary = new_with_file(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/pbm/test/bitmap.binary.pbm"))).unwrap();
+
+        assert_eq!((10, 2, 1), (ascii.width(), ascii.height(), ascii.max_value()));
+        assert_eq!(Pixel { red: 0, green: 0, blue: 0 }, ascii.pixels()[0]);
+        assert_eq!(Pixel { red: 1, green: 1, blue: 1 }, ascii.pixels()[1]);
+        assert_eq!(Format::Binary, binary.format());
+        assert_eq!(ascii.pixels(), binary.pixels());
+    }
+
+    #[test]
+    fn test_round_trip_and_promotion() {
+        let binary_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/pbm/test/bitmap.test.pbm"));
+        let pixmap_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/pbm/test/bitmap.test.ppm"));
+
+        let mut image = open(get_test_file_path()).unwrap();
+        image.save_as(binary_path, Format::Binary).unwrap();
+        assert_eq!(image.pixels(), open(binary_path).unwrap().pixels());
+
+        image.to_pixmap();
+        image.save_as(pixmap_path, Format::Ascii).unwrap();
+
+        let pixmap = open(pixmap_path).unwrap();
+        assert_eq!(Kind::Pixmap, pixmap.kind());
+        assert_eq!(1, pixmap.max_value());
+        assert_eq!(image.pixels(), pixmap.pixels());
+    }
+}
@@ -0,0 +1,162 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::error::PpmError;
+use crate::header::{read_header_tokens, write_header, Header, Tokenizer, PREALLOCATED_SAMPLES};
+use crate::image::{Format, Image, Kind, Pixel};
+use crate::p6::{bytes_per_sample, decode_samples, read_bytes};
+
+/*
+    new_with_file(filename)
+
+    Load a greymap (PGM) file into an Image, whatever its encoding (P2 or P5).
+    Each grey sample is stored as a pixel with three equal channels.
+
+    Will return Result with Err if the file is not a greymap or its pixels section is invalid.
+*/
+pub fn new_with_file(filename: &Path) -> Result<Image, PpmError> {
+
+    new_with_reader(BufReader::new(File::open(filename)?))
+}
+
+// Same as new_with_file(), for any buffered reader: a Cursor over bytes already in memory, stdin or a pipe.
+pub fn new_with_reader<R: BufRead>(mut reader: R) -> Result<Image, PpmError> {
+
+    let mut tokens = Tokenizer::new(&mut reader);
+    let header = read_header_tokens(&mut tokens)?;
+
+    if header.kind != Kind::Greymap {
+        return Err(PpmError::BadMagic(header.kind.magic_number(header.format).to_string()));
+    }
+
+    let pixels = read_pixels(&mut tokens, &header)?;
+    Ok(Image::with_pixels(&header, pixels))
+}
+
+// Read a greymap pixels section, right after the headers read by the same tokenizer.
+pub(crate) fn read_pixels<R: BufRead>(tokens: &mut Tokenizer<R>, header: &Header) -> Result<Vec<Pixel>, PpmError> {
+
+    let count = header.width * header.height;
+    let samples = match header.format {
+        Format::Ascii => read_ascii_samples(tokens, count, header.max_value)?,
+        Format::Binary => read_binary_samples(tokens, count, header.max_value)?,
+    };
+
+    Ok(samples
+        .into_iter()
+        .map(|grey| Pixel {
+            red: grey,
+            green: grey,
+            blue: grey,
+        })
+        .collect())
+}
+
+/*
+    Read count ASCII samples, right after the headers read by the same tokenizer.
+    Samples may be spread over lines in any way, with comments in between. Errors report the line and column of the faulty sample.
+*/
+pub(crate) fn read_ascii_samples<R: BufRead>(tokens: &mut Tokenizer<R>, count: usize, max_value: usize) -> Result<Vec<u16>, PpmError> {
+
+    let mut samples = Vec::with_capacity(count.min(PREALLOCATED_SAMPLES));
+
+    // Stop right after the last sample, another image may follow.
+    while samples.len() < count {
+        let token = match tokens.next_token()? {
+            Some(token) => token,
+            None => break,
+        };
+
+        match token.number() {
+            Some(sample) if sample <= max_value => samples.push(sample as u16),
+            _ => return Err(PpmError::InvalidSample { line: token.line, column: token.column }),
+        }
+    }
+
+    if samples.len() < count {
+        return Err(PpmError::TruncatedPixelData { expected: count, got: samples.len() });
+    }
+
+    Ok(samples)
+}
+
+/*
+    Read count binary samples, one byte each up to 255 max value, two big-endian bytes above.
+    Just like ASCII ones, samples above the max value are refused, with the line and column of their first byte.
+*/
+pub(crate) fn read_binary_samples<R: BufRead>(tokens: &mut Tokenizer<R>, count: usize, max_value: usize) -> Result<Vec<u16>, PpmError> {
+
+    let sample_bytes = bytes_per_sample(max_value);
+
+    let start = tokens.position();
+    let bytes = read_bytes(tokens, count * sample_bytes)?;
+
+    if bytes.len() < count * sample_bytes {
+        return Err(PpmError::TruncatedPixelData { expected: count, got: bytes.len() / sample_bytes });
+    }
+
+    let mut samples = vec![0; count];
+    decode_samples(&bytes, max_value, || Ok(start), &mut samples)?;
+
+    Ok(samples)
+}
+
+//  Function that writes an image as a greymap (P2 or P5), using the grey level of each pixel.
+pub fn write_image<W: Write>(image: &Image, format: Format, writer: &mut W) -> Result<(), PpmError> {
+    write_header(writer, Kind::Greymap, format, (image.width, image.height), image.max_value as usize, &image.comments)?;
+
+    let sample_bytes = bytes_per_sample(image.max_value as usize);
+
+    for pixel in image.pixels.iter() {
+        let grey = pixel.grey();
+
+        match (format, sample_bytes) {
+            (Format::Ascii, _) => writeln!(writer, "{}", grey)?,
+            (Format::Binary, 1) => writer.write_all(&[grey as u8])?,
+            (Format::Binary, _) => writer.write_all(&grey.to_be_bytes())?,
+        }
+    }
+
+    Ok(())
+}
+
+// Module for testing
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::image::open;
+
+    fn get_test_file_path() -> &'static Path {
+        Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/pgm/test/greymap.pgm"))
+    }
+
+    #[test]
+    fn test_ascii_and_binary_match() {
+        let ascii = new_with_file(get_test_file_path()).unwrap();
+        let binary = new_with_file(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/pgm/test/greymap.binary.pgm"))).unwrap();
+
+        assert_eq!((4, 2, 15), (ascii.width(), ascii.height(), ascii.max_value()));
+        assert_eq!(Pixel { red: 7, green: 7, blue: 7 }, ascii.pixels()[1]);
+        assert_eq!(Format::Binary, binary.format());
+        assert_eq!(ascii.pixels(), binary.pixels());
+    }
+
+    #[test]
+    fn test_greyscale_to_p5() {
+        let out_file_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/pgm/test/alaska.test.pgm"));
+
+        let mut image = open(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/p6/test/alaska.ppm"))).unwrap();
+        image.to_greymap();
+        image.save(out_file_path).unwrap();
+
+        // A single byte per pixel after the "P5\n512 512\n255\n" headers.
+        assert_eq!(15 + 512 * 512, std::fs::metadata(out_file_path).unwrap().len());
+
+        let greymap = open(out_file_path).unwrap();
+        assert_eq!(Kind::Greymap, greymap.kind());
+        assert_eq!(image.pixels(), greymap.pixels());
+    }
+}
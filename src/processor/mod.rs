@@ -0,0 +1,157 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+extern crate num_cpus;
+
+// A unit of work for the pool, it owns everything it touches.
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/*
+    Our Processor structure, a persistent pool of worker threads.
+    Workers are spawned once, when the Processor is created, and joined when it's dropped.
+    A single Processor can be shared by many images, even from different threads at the same time.
+*/
+pub struct Processor {
+    threads: usize,
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Processor {
+
+    /*
+        new(threads)
+
+        Create a pool of threads workers.
+        With a single thread no worker is spawned, jobs simply run on the caller's thread.
+
+        0 is treated as 1.
+    */
+    pub fn new(threads: usize) -> Processor {
+        let threads = threads.max(1);
+
+        if threads == 1 {
+            return Processor {
+                threads,
+                sender: None,
+                workers: vec![],
+            };
+        }
+
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..threads)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || work(receiver))
+            })
+            .collect();
+
+        Processor {
+            threads,
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    // How many jobs can run at the same time.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    // Run a job on the next available worker, or right away on a single threaded Processor.
+    pub(crate) fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        match &self.sender {
+            Some(sender) => sender.send(Box::new(job)).expect("processor workers are gone"),
+            None => job(),
+        }
+    }
+}
+
+// A Processor with one worker per logical CPU.
+impl Default for Processor {
+    fn default() -> Processor {
+        Processor::new(num_cpus::get())
+    }
+}
+
+impl Drop for Processor {
+    fn drop(&mut self) {
+        // Closing the channel lets every worker leave its loop once the queued jobs are done.
+        self.sender = None;
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+// Worker loop, pick jobs until the Processor is dropped.
+fn work(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+
+        match job {
+            // A panicking job must not take the worker down with it, the caller notices its missing result.
+            Ok(job) => {
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+// Module for testing
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_jobs_run_on_workers() {
+        let processor = Processor::new(3);
+        assert_eq!(3, processor.threads());
+
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..10 {
+            let sender = sender.clone();
+            processor.execute(move || sender.send(i).unwrap());
+        }
+        drop(sender);
+
+        let mut results: Vec<i32> = receiver.iter().collect();
+        results.sort();
+        assert_eq!((0..10).collect::<Vec<i32>>(), results);
+    }
+
+    #[test]
+    fn test_single_threaded() {
+        let processor = Processor::new(0);
+        assert_eq!(1, processor.threads());
+
+        let caller = thread::current().id();
+        let (sender, receiver) = mpsc::channel();
+        processor.execute(move || sender.send(thread::current().id()).unwrap());
+
+        assert_eq!(caller, receiver.recv().unwrap());
+    }
+
+    #[test]
+    fn test_survives_panicking_job() {
+        let processor = Processor::new(2);
+
+        for _ in 0..2 {
+            processor.execute(|| panic!("job failure"));
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        processor.execute(move || sender.send(42).unwrap());
+        assert_eq!(42, receiver.recv().unwrap());
+    }
+}
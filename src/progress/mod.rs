@@ -0,0 +1,84 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/*
+    Our CancellationToken structure, a flag shared between the caller and a running process.
+    Clone it, hand one copy to the process through a Monitor, and call cancel() on the other one from anywhere.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    // Ask the process to stop, it does so before its next chunk.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+// How much of the pixels section was written so far, in binary pixels bytes (width * height * samples per pixel * bytes per sample in total).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Progress {
+    pub done: u64,
+    pub total: u64,
+}
+
+impl Progress {
+
+    // Between 0 and 1, an empty image is always done.
+    pub fn ratio(&self) -> f64 {
+        match self.total {
+            0 => 1.0,
+            total => self.done as f64 / total as f64,
+        }
+    }
+}
+
+/*
+    Our Monitor structure, what a long-running process reports to and listens to.
+
+    Monitor::new().on_progress(|progress| println!("{:.0}%", progress.ratio() * 100.0)).with_cancellation(token.clone())
+
+    The progress callback is called after each written chunk, from the process' writer thread, so it must be Send and Sync.
+*/
+#[derive(Clone, Default)]
+pub struct Monitor {
+    on_progress: Option<Arc<dyn Fn(Progress) + Send + Sync>>,
+    cancellation: CancellationToken,
+}
+
+impl Monitor {
+
+    // A monitor which reports nothing and is never cancelled.
+    pub fn new() -> Monitor {
+        Monitor::default()
+    }
+
+    pub fn on_progress<F: Fn(Progress) + Send + Sync + 'static>(mut self, callback: F) -> Monitor {
+        self.on_progress = Some(Arc::new(callback));
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Monitor {
+        self.cancellation = cancellation;
+        self
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    pub(crate) fn report(&self, progress: Progress) {
+        if let Some(callback) = &self.on_progress {
+            callback(progress);
+        }
+    }
+}